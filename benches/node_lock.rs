@@ -0,0 +1,126 @@
+/* 
+ * This file is part of the Sicily distribution (https://github.com/JeepYiheihou/sicily).
+ * Copyright (c) 2021 Jiachen Bai.
+ * 
+ * This program is free software: you can redistribute it and/or modify  
+ * it under the terms of the GNU General Public License as published by  
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU 
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License 
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+/*
+ * Compare the throughput of concurrent lookups on a single virtual node while
+ * stabilization keeps updating its fingers, with the node behind the reader-writer
+ * lock used by NodeList, and behind the exclusive lock it used to be.
+ *
+ * Run with `cargo bench --bench node_lock`.
+ */
+
+use criterion::{ criterion_group, criterion_main, Criterion };
+use num::BigUint;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use sicily::config;
+use sicily::metrics::Metrics;
+use sicily::node::{ Node, NodeList };
+
+/* Concurrent lookup tasks, and lookups per task. */
+const READERS: usize = 8;
+const LOOKUPS: usize = 1000;
+/* Finger updates done meanwhile, like a stabilizer would. */
+const WRITES: usize = 20;
+
+fn keys(id_bits: u8) -> Vec<BigUint> {
+    let modulus = BigUint::from(2u32).pow(id_bits as u32);
+    (0..LOOKUPS as u64)
+        .map(|i| (BigUint::from(i) * BigUint::from(0x9e37_79b9_7f4a_7c15u64)) % &modulus)
+        .collect()
+}
+
+async fn lookups_with_rwlock(node_list: Arc<NodeList>, keys: Arc<Vec<BigUint>>) {
+    let mut tasks = Vec::new();
+    for _ in 0..READERS {
+        let node_list = node_list.clone();
+        let keys = keys.clone();
+        tasks.push(tokio::spawn(async move {
+            for key in keys.iter() {
+                let node = node_list.node_list[0].read().await;
+                node.closest_preceding_finger(key.clone()).unwrap();
+            }
+        }));
+    }
+    let writer = tokio::spawn(async move {
+        for i in 0..WRITES {
+            let mut node = node_list.node_list[0].write().await;
+            let n = i % node.get_finger_len();
+            let location = node.get_finger(n).unwrap();
+            node.set_finger(n, Some(location)).unwrap();
+        }
+    });
+    for task in tasks {
+        task.await.unwrap();
+    }
+    writer.await.unwrap();
+}
+
+async fn lookups_with_mutex(node: Arc<Mutex<Node>>, keys: Arc<Vec<BigUint>>) {
+    let mut tasks = Vec::new();
+    for _ in 0..READERS {
+        let node = node.clone();
+        let keys = keys.clone();
+        tasks.push(tokio::spawn(async move {
+            for key in keys.iter() {
+                let node = node.lock().await;
+                node.closest_preceding_finger(key.clone()).unwrap();
+            }
+        }));
+    }
+    let writer = tokio::spawn(async move {
+        for i in 0..WRITES {
+            let mut node = node.lock().await;
+            let n = i % node.get_finger_len();
+            let location = node.get_finger(n).unwrap();
+            node.set_finger(n, Some(location)).unwrap();
+        }
+    });
+    for task in tasks {
+        task.await.unwrap();
+    }
+    writer.await.unwrap();
+}
+
+fn bench_node_lock(c: &mut Criterion) {
+    let config = Arc::new(config::parse_params_from(&[
+        "sicily",
+        "--host", "127.0.0.1",
+        "--id-bits", "64",
+        "--virtual-node-number", "1",
+    ]).unwrap());
+    let keys = Arc::new(keys(config.id_bits));
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(READERS)
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("concurrent_lookups");
+    let node_list = Arc::new(NodeList::new(config.clone()));
+    group.bench_function("rwlock", |b| {
+        b.to_async(&runtime).iter(|| lookups_with_rwlock(node_list.clone(), keys.clone()))
+    });
+    let node = Arc::new(Mutex::new(Node::new(config.clone(), 0, Arc::new(Metrics::new()))));
+    group.bench_function("mutex", |b| {
+        b.to_async(&runtime).iter(|| lookups_with_mutex(node.clone(), keys.clone()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_node_lock);
+criterion_main!(benches);
\ No newline at end of file
@@ -16,16 +16,14 @@
  */
 
 use num::bigint::BigUint;
-use sha2::{ Sha256, Digest };
+
+use crate::hash::HashAlgorithm;
 
 /*
- * Given a string, hash it with SHA256 into a big unit.
+ * Given a string, hash it with the given algorithm into a big unit.
  */
-fn hash(input: &String) -> BigUint {
-    let mut hasher = Sha256::new();
-    hasher.update(input.as_bytes());
-    let arr = hasher.finalize();
-    BigUint::from_bytes_be(&arr)
+fn hash(algorithm: &dyn HashAlgorithm, input: &str) -> BigUint {
+    algorithm.hash(input.as_bytes())
 }
 
 /* 
@@ -69,23 +67,24 @@ pub fn is_in_range(
     }
 }
 
-pub fn compute_identifier(bits: u32, input: &String) -> BigUint {
+pub fn compute_identifier(algorithm: &dyn HashAlgorithm, bits: u32, input: &str) -> BigUint {
     let base = BigUint::from_bytes_be(&[2]);
     let divisor = base.pow(bits);
-    let hash = hash(input);
-    let identifier = hash % divisor;
-    identifier
+    let hash = hash(algorithm, input);
+    hash % divisor
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::hash::Sha256Hash;
 
     #[test]
     fn test_hash() {
         let src = "207.216.57.167:8820:6".to_string();
         let expected_hash = "73983030965240321521725464828347026369133146436118419434250862939976471883122";
-        assert_eq!(format!("{}", hash(&src)), expected_hash);
+        assert_eq!(format!("{}", hash(&Sha256Hash, &src)), expected_hash);
     }
 
     #[test]
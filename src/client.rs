@@ -16,46 +16,446 @@
  */
 
 use bytes::BytesMut;
+use rand::prelude::*;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{ AsyncReadExt, AsyncWriteExt };
 use tokio::net::{ TcpStream };
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::timeout;
 
-use crate::command::{ Request, Response };
+use crate::codec;
+use crate::command::{ Hello, Request, Response };
 use crate::config::Config;
 use crate::constants::*;
+use crate::error::SicilyError;
 use crate::location::Location;
+use crate::metrics::Metrics;
+use crate::node::NodeList;
+use crate::pool::Checkout;
 use crate::utils::Result;
 
+/*
+ * Client side of node to node traffic. It always talks the binary protocol.
+ */
+#[derive(Debug)]
 pub struct Client {
     pub socket: TcpStream,
     pub buffer: BytesMut,
+    pub read_timeout: Duration,
+    metrics: Arc<Metrics>,
+    _permit: OwnedSemaphorePermit,
 }
 
 impl Client {
-    pub async fn new(location: &Location) -> Result<Self> {
-        let addr = location.to_addr()?;
-        let socket = TcpStream::connect(addr).await?;
-        let buffer = BytesMut::with_capacity(OUTPUT_BUFFER_SIZE);
-        let client = Self {
+    /*
+     * Open a connection to the node at location, holding the permit the pool gave
+     * for it until the connection is closed.
+     */
+    pub async fn new(
+        location: &Location,
+        permit: OwnedSemaphorePermit,
+        metrics: Arc<Metrics>,
+        config: Arc<Config>,
+    ) -> Result<Self> {
+        let addr = location.to_addr();
+        let connect_timeout = Duration::from_millis(config.connect_timeout);
+        let mut socket = match timeout(connect_timeout, TcpStream::connect(addr)).await {
+            Ok(socket) => socket?,
+            Err(_) => {
+                return Err(SicilyError::Timeout(
+                    format!("Connecting to {} timed out after {} ms.", location, config.connect_timeout)
+                ));
+            }
+        };
+        socket.write_all(&[BINARY_PROTOCOL_HANDSHAKE]).await?;
+        let buffer = BytesMut::with_capacity(config.output_buffer_size);
+        metrics.outbound_connection_opened();
+        let mut client = Self {
             socket,
             buffer,
+            read_timeout: Duration::from_millis(config.read_timeout),
+            metrics,
+            _permit: permit,
         };
+        client.say_hello(location, &config).await?;
         Ok(client)
     }
 
-    pub async fn send_request(&mut self, request: Request) -> Result<()> {
-        let req_string = request.serialize()?;
-        self.socket.write_all(req_string.as_bytes()).await?;
+    /*
+     * Say hello to the server, which must be the first request of the connection.
+     * Fail with SicilyError::Incompatible if either side can't talk to the other.
+     */
+    async fn say_hello(&mut self, location: &Location, config: &Config) -> Result<()> {
+        let request = Request::Hello {
+            virtual_node_id: location.virtual_node_id,
+            hello: Hello::new(config),
+        };
+        self.send_request(&request).await?;
+        match self.receive().await? {
+            Response::Hello { hello } => {
+                hello.check(config).map_err(|e| {
+                    SicilyError::Incompatible(format!("Cannot talk to {}. {}", location, e.message()))
+                })?;
+            },
+            _ => {
+                return Err(
+                    "Error receiving response while doing HELLO. Got unexpected response type."
+                    .into()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn send_request(&mut self, request: &Request) -> Result<()> {
+        let mut frame = BytesMut::new();
+        request.encode(&mut frame)?;
+        self.socket.write_all(&frame).await?;
         self.socket.flush().await?;
         Ok(())
     }
 
-    pub async fn receive(&mut self, config: Arc<Config>) -> Result<Response> {
-        let n = self.socket.read_buf(&mut self.buffer).await?;
-        if n == 0 {
-            return Err("[Client side] Error receiving response. Server side closed the connection.".into());
+    /*
+     * Receive a response. If the server answered with an error, it is returned as the
+     * same SicilyError the server reported.
+     */
+    pub async fn receive(&mut self) -> Result<Response> {
+        let frame = self.receive_frame().await?;
+        Response::decode(&frame)
+    }
+
+    /*
+     * Receive the next frame, without decoding it.
+     */
+    async fn receive_frame(&mut self) -> Result<BytesMut> {
+        loop {
+            if let Some(frame) = codec::take_frame(&mut self.buffer)? {
+                return Ok(frame);
+            }
+            let n = match timeout(self.read_timeout, self.socket.read_buf(&mut self.buffer)).await {
+                Ok(n) => n?,
+                Err(_) => {
+                    return Err(SicilyError::Timeout(
+                        format!("Receiving response timed out after {} ms.", self.read_timeout.as_millis())
+                    ));
+                }
+            };
+            if n == 0 {
+                return Err("[Client side] Error receiving response. Server side closed the connection.".into());
+            }
+        }
+    }
+
+    /*
+     * Check whether an idle connection was closed by the peer, for example because
+     * it restarted, without sending anything on it.
+     */
+    fn is_closed(&mut self) -> bool {
+        match self.socket.try_read_buf(&mut self.buffer) {
+            Ok(_) => true,
+            Err(e) => e.kind() != std::io::ErrorKind::WouldBlock,
+        }
+    }
+
+    /*
+     * Send a request and wait for the frame of its response.
+     */
+    async fn exchange(&mut self, request: &Request) -> Result<BytesMut> {
+        self.send_request(request).await?;
+        self.receive_frame().await
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.metrics.outbound_connection_closed();
+    }
+}
+
+/*
+ * Send request to the node at location and wait for its response.
+ * An attempt that failed before any byte of the request was written, such as a failed
+ * connect or handshake, is retried up to request_retries times, with exponential backoff
+ * and jitter in between. An attempt that failed after that, for example because reading
+ * the response timed out, may have reached the peer already, so it is only retried if
+ * the request is idempotent. The whole call, retries included, is bounded by request_timeout.
+ * Errors are reported as SicilyError: Timeout if the call didn't complete in time,
+ * Peer if the node couldn't be reached, or the error the node answered with.
+ * An error answer is not retried.
+ */
+pub async fn call(
+    location: &Location,
+    request: Request,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Response> {
+    let request_timeout = Duration::from_millis(config.request_timeout);
+    let frame = match timeout(request_timeout, call_with_retries(location, &request, &node_list, config.clone())).await {
+        Ok(Ok(frame)) => frame,
+        Ok(Err(e)) => {
+            return Err(match e {
+                SicilyError::Internal(message) | SicilyError::Peer(message) => {
+                    SicilyError::Peer(format!("Request to {} failed. {}", location, message))
+                },
+                e => e,
+            });
+        },
+        Err(_) => {
+            return Err(SicilyError::Timeout(
+                format!("Request to {} timed out after {} ms.", location, config.request_timeout)
+            ));
+        }
+    };
+    Response::decode(&frame)
+}
+
+async fn call_with_retries(
+    location: &Location,
+    request: &Request,
+    node_list: &NodeList,
+    config: Arc<Config>,
+) -> Result<BytesMut> {
+    let mut attempt = 0;
+    loop {
+        match call_once(location, request, node_list, config.clone()).await {
+            Ok(frame) => return Ok(frame),
+            Err(AttemptError { error: e, written }) => {
+                /* There is no point retrying with a peer that can't talk to the local node. */
+                let incompatible = matches!(e, SicilyError::Incompatible(_));
+                let unsafe_to_resend = written && !request.is_idempotent();
+                if incompatible || unsafe_to_resend || attempt >= config.request_retries {
+                    return Err(e);
+                }
+                let delay = backoff_delay(attempt, config.retry_backoff);
+                log::debug!(
+                    "Request to {} failed, retrying in {} ms. Error message: {}.",
+                    location,
+                    delay.as_millis(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/*
+ * Compute the delay before retry number attempt: the base doubles with every attempt,
+ * and half of it is randomized so that peers don't retry in lockstep.
+ */
+fn backoff_delay(attempt: u32, base: u64) -> Duration {
+    let exp = base.saturating_mul(1u64 << attempt.min(16));
+    let half = exp / 2;
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    Duration::from_millis(half + rng.gen_range(0..=half))
+}
+
+/*
+ * Failure of a single attempt of a call. written tells whether the request may
+ * have reached the peer.
+ */
+struct AttemptError {
+    error: SicilyError,
+    written: bool,
+}
+
+/*
+ * Single attempt of a call.
+ * An idle connection from the pool is reused if there is one. Otherwise a new one
+ * is opened, once the pool allows one more connection to the peer. Pooled connections
+ * the peer has closed, for example because it restarted, are dropped before
+ * anything is written to them. If a pooled connection breaks once the request is
+ * written, we only reconnect and send it again if the request is idempotent.
+ * The connection goes back to the pool once the response is received, even if
+ * the response is an error.
+ */
+async fn call_once(
+    location: &Location,
+    request: &Request,
+    node_list: &NodeList,
+    config: Arc<Config>,
+) -> std::result::Result<BytesMut, AttemptError> {
+    let addr = location.to_addr();
+    let permit = loop {
+        let mut client = match node_list.pool.checkout(&addr, &config).await {
+            Checkout::Idle(client) => client,
+            Checkout::New(permit) => break permit,
+        };
+        if client.is_closed() {
+            log::debug!("Pooled connection to {} was closed by the peer, dropping it.", addr);
+            continue;
+        }
+        match client.exchange(request).await {
+            Ok(frame) => {
+                release(addr, client, node_list, &config);
+                return Ok(frame);
+            },
+            Err(e) if request.is_idempotent() => {
+                log::debug!("Pooled connection to {} is broken, reconnecting. Error message: {}.", addr, e);
+            },
+            Err(e) => {
+                return Err(AttemptError { error: e, written: true });
+            }
+        }
+    };
+
+    let mut client = Client::new(location, permit, node_list.metrics.clone(), config.clone()).await
+        .map_err(|e| AttemptError { error: e, written: false })?;
+    let frame = client.exchange(request).await
+        .map_err(|e| AttemptError { error: e, written: true })?;
+    release(addr, client, node_list, &config);
+    Ok(frame)
+}
+
+/*
+ * Put a client back to the pool, unless it holds unexpected leftover bytes.
+ */
+fn release(addr: String, client: Client, node_list: &NodeList, config: &Config) {
+    if client.buffer.is_empty() {
+        node_list.pool.put(addr, client, config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use num::BigUint;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use tokio::net::TcpListener;
+
+    /*
+     * Start a peer that answers HELLO, and PING after ping_delay, but never answers
+     * any other request. Return its location, the number of connections it accepted,
+     * and the number of requests it left unanswered.
+     */
+    async fn fake_peer(config: Arc<Config>, ping_delay: Duration) -> (Location, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = Location {
+            ip: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            virtual_node_id: 0,
+            identifier: BigUint::from(0u32),
+        };
+        let connections = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(AtomicUsize::new(0));
+        let (accepted, counter) = (connections.clone(), received.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let counter = counter.clone();
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let mut buffer = BytesMut::new();
+                    if socket.read_u8().await.unwrap() != BINARY_PROTOCOL_HANDSHAKE {
+                        return;
+                    }
+                    loop {
+                        while let Some(frame) = codec::take_frame(&mut buffer).unwrap() {
+                            match Request::decode(&frame).unwrap() {
+                                Request::Hello { .. } => {
+                                    let mut reply = BytesMut::new();
+                                    Response::Hello { hello: Hello::new(&config) }.encode(&mut reply).unwrap();
+                                    socket.write_all(&reply).await.unwrap();
+                                },
+                                Request::Ping { .. } => {
+                                    tokio::time::sleep(ping_delay).await;
+                                    let mut reply = BytesMut::new();
+                                    Response::Ping.encode(&mut reply).unwrap();
+                                    socket.write_all(&reply).await.unwrap();
+                                },
+                                _ => {
+                                    counter.fetch_add(1, Ordering::SeqCst);
+                                }
+                            }
+                        }
+                        if socket.read_buf(&mut buffer).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (location, connections, received)
+    }
+
+    #[tokio::test]
+    async fn test_only_idempotent_requests_are_resent_after_being_written() {
+        let config = Arc::new(config::parse_params_from(&[
+            "sicily",
+            "--read-timeout", "100",
+            "--request-timeout", "5000",
+            "--request-retries", "2",
+            "--retry-backoff", "1",
+        ]).unwrap());
+        let node_list = Arc::new(NodeList::new(config.clone()));
+        let (location, _, received) = fake_peer(config.clone(), Duration::ZERO).await;
+
+        /* The PUT may have been applied, so it is sent once. */
+        let request = Request::Put {
+            virtual_node_id: 0,
+            key: BigUint::from(1u32),
+            value: "value".to_string(),
+            forwarded: false,
+        };
+        assert!(call(&location, request, node_list.clone(), config.clone()).await.is_err());
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        /* The GET is sent again on every retry. */
+        let request = Request::Get {
+            virtual_node_id: 0,
+            key: BigUint::from(1u32),
+            forwarded: false,
+        };
+        assert!(call(&location, request, node_list.clone(), config.clone()).await.is_err());
+        assert_eq!(received.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_pool_caps_connections_per_peer() {
+        let config = Arc::new(config::parse_params_from(&[
+            "sicily",
+            "--request-timeout", "5000",
+            "--pool-max-connections-per-peer", "2",
+        ]).unwrap());
+        let node_list = Arc::new(NodeList::new(config.clone()));
+        let (location, connections, _) = fake_peer(config.clone(), Duration::from_millis(20)).await;
+
+        /* Concurrent requests beyond the cap wait for a connection to be put back. */
+        let calls: Vec<_> = (0..6).map(|_| {
+            let (location, node_list, config) = (location.clone(), node_list.clone(), config.clone());
+            tokio::spawn(async move {
+                call(&location, Request::Ping { virtual_node_id: 0 }, node_list, config).await
+            })
+        }).collect();
+        for handle in calls {
+            assert!(matches!(handle.await.unwrap().unwrap(), Response::Ping));
         }
-        let response = Response::parse_from_buf(&self.buffer, config)?;
-        Ok(response)
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        assert_eq!(node_list.pool.idle_len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_evicts_expired_connections_when_taking() {
+        let config = Arc::new(config::parse_params_from(&[
+            "sicily",
+            "--pool-idle-timeout", "50",
+        ]).unwrap());
+        let node_list = Arc::new(NodeList::new(config.clone()));
+        let (location, connections, _) = fake_peer(config.clone(), Duration::ZERO).await;
+
+        call(&location, Request::Ping { virtual_node_id: 0 }, node_list.clone(), config.clone()).await.unwrap();
+        assert_eq!(node_list.pool.idle_len(), 1);
+
+        /* Taking a connection to any peer evicts the expired ones of every peer. */
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(node_list.pool.take("127.0.0.1:1", &config).is_none());
+        assert_eq!(node_list.pool.idle_len(), 0);
+
+        call(&location, Request::Ping { virtual_node_id: 0 }, node_list.clone(), config.clone()).await.unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
     }
 }
\ No newline at end of file
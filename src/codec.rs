@@ -0,0 +1,1117 @@
+/* 
+ * This file is part of the Sicily distribution (https://github.com/JeepYiheihou/sicily).
+ * Copyright (c) 2021 Jiachen Bai.
+ * 
+ * This program is free software: you can redistribute it and/or modify  
+ * it under the terms of the GNU General Public License as published by  
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU 
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License 
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+/*
+ * Framing of requests and responses on the wire.
+ *
+ * The text protocol, used by interactive clients, frames every request as a
+ * line terminated by "\n" or "\r\n".
+ *
+ * The binary protocol is used for node to node traffic.
+ * A connection switches to the binary protocol when its first byte is
+ * BINARY_PROTOCOL_HANDSHAKE. After that, every message is a frame of:
+ *   - u32 big endian length of the rest of the frame,
+ *   - u8 type tag,
+ *   - the fields of the message.
+ * A failed request is answered with an error frame instead of its response,
+ * holding the code and the message of the error as strings.
+ *
+ * Fields are encoded as:
+ *   - integers: big endian, with their own width.
+ *   - identifiers: IDENTIFIER_BYTES bytes big endian, zero padded.
+ *   - strings: u32 length followed by UTF-8 bytes.
+ *   - locations: u8 host length, host bytes, u16 port, u8 virtual node id, identifier.
+ *   - booleans: u8 0 for false, 1 for true.
+ *   - options: u8 0 for None, 1 for Some followed by the value.
+ *   - lookup modes: u8 0 for iterative, 1 for recursive.
+ *   - hops: location, followed by u64 round-trip time in microseconds.
+ *   - hellos: u16 protocol version, cluster name string, u8 identifier bits,
+ *     hash algorithm name string.
+ *   - lists: u32 length followed by the items.
+ */
+
+use bytes::{ BufMut, BytesMut };
+use num::BigUint;
+use std::time::Duration;
+
+use crate::command::{ Hello, Request, Response };
+use crate::config::LookupMode;
+use crate::constants::*;
+use crate::error::SicilyError;
+use crate::location::Location;
+use crate::process::Hop;
+use crate::utils::Result;
+
+/* Request type tags. */
+const REQ_CLOSEST_PRECEDING_FINGER: u8 = 0x01;
+const REQ_CONFIRM_TRANSFER: u8 = 0x02;
+const REQ_DELETE: u8 = 0x03;
+const REQ_GET: u8 = 0x04;
+const REQ_GET_PREDECESSOR: u8 = 0x05;
+const REQ_GET_SUCCESSOR: u8 = 0x06;
+const REQ_GET_SUCCESSOR_LIST: u8 = 0x07;
+const REQ_HANDOFF: u8 = 0x08;
+const REQ_INFO: u8 = 0x09;
+const REQ_JOIN: u8 = 0x0a;
+const REQ_LEAVE: u8 = 0x0b;
+const REQ_LOOKUP: u8 = 0x0c;
+const REQ_LOOKUP_KEY: u8 = 0x0d;
+const REQ_NOTIFY: u8 = 0x0e;
+const REQ_PING: u8 = 0x0f;
+const REQ_PUT: u8 = 0x10;
+const REQ_REPLACE_PREDECESSOR: u8 = 0x11;
+const REQ_REPLACE_SUCCESSOR: u8 = 0x12;
+const REQ_TRANSFER: u8 = 0x13;
+const REQ_FIND_SUCCESSOR: u8 = 0x14;
+const REQ_LOOKUP_TRACE: u8 = 0x15;
+const REQ_HELLO: u8 = 0x16;
+
+/* Response type tags. Same as the request ones, with the highest bit set. */
+const RES_CLOSEST_PRECEDING_FINGER: u8 = 0x81;
+const RES_CONFIRM_TRANSFER: u8 = 0x82;
+const RES_DELETE: u8 = 0x83;
+const RES_GET: u8 = 0x84;
+const RES_GET_PREDECESSOR: u8 = 0x85;
+const RES_GET_SUCCESSOR: u8 = 0x86;
+const RES_GET_SUCCESSOR_LIST: u8 = 0x87;
+const RES_HANDOFF: u8 = 0x88;
+const RES_INFO: u8 = 0x89;
+const RES_JOIN: u8 = 0x8a;
+const RES_LEAVE: u8 = 0x8b;
+const RES_LOOKUP: u8 = 0x8c;
+const RES_LOOKUP_KEY: u8 = 0x8d;
+const RES_NOTIFY: u8 = 0x8e;
+const RES_PING: u8 = 0x8f;
+const RES_PUT: u8 = 0x90;
+const RES_REPLACE_PREDECESSOR: u8 = 0x91;
+const RES_REPLACE_SUCCESSOR: u8 = 0x92;
+const RES_TRANSFER: u8 = 0x93;
+const RES_FIND_SUCCESSOR: u8 = 0x94;
+const RES_LOOKUP_TRACE: u8 = 0x95;
+const RES_HELLO: u8 = 0x96;
+
+/* Error frame tag. */
+const RES_ERROR: u8 = 0xff;
+
+/* Length of the frame length prefix. */
+const LENGTH_BYTES: usize = 4;
+
+/*
+ * Split the next complete line off the buffer, including its line terminator.
+ * Return None if the buffer doesn't hold a complete line yet.
+ */
+pub fn take_line(buf: &mut BytesMut) -> Result<Option<BytesMut>> {
+    match buf.iter().position(|byte| *byte == b'\n') {
+        Some(pos) => Ok(Some(buf.split_to(pos + 1))),
+        None => {
+            if buf.len() > MAX_FRAME_SIZE {
+                return Err(
+                    format!("Invalid line. Line is longer than {} bytes.", MAX_FRAME_SIZE)
+                    .into());
+            }
+            Ok(None)
+        }
+    }
+}
+
+/*
+ * Split the next complete frame off the buffer, without the length prefix.
+ * Return None if the buffer doesn't hold a complete frame yet.
+ */
+pub fn take_frame(buf: &mut BytesMut) -> Result<Option<BytesMut>> {
+    if buf.len() < LENGTH_BYTES {
+        return Ok(None);
+    }
+    let mut length_bytes = [0u8; LENGTH_BYTES];
+    length_bytes.copy_from_slice(&buf[..LENGTH_BYTES]);
+    let length = u32::from_be_bytes(length_bytes) as usize;
+    if length == 0 || length > MAX_FRAME_SIZE {
+        return Err(
+            format!("Invalid frame. Frame length {} is out of range.", length)
+            .into());
+    }
+    if buf.len() < LENGTH_BYTES + length {
+        return Ok(None);
+    }
+    let _ = buf.split_to(LENGTH_BYTES);
+    Ok(Some(buf.split_to(length)))
+}
+
+impl Request {
+    /*
+     * Encode the request as a frame, appended to buf.
+     */
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<()> {
+        let mut body = BytesMut::new();
+        match self {
+            Request::ClosestPrecedingFinger { virtual_node_id, key } => {
+                body.put_u8(REQ_CLOSEST_PRECEDING_FINGER);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, key)?;
+            },
+            Request::ConfirmTransfer { virtual_node_id, entries } => {
+                body.put_u8(REQ_CONFIRM_TRANSFER);
+                body.put_u8(*virtual_node_id);
+                put_entries(&mut body, entries)?;
+            },
+            Request::Delete { virtual_node_id, key, forwarded } => {
+                body.put_u8(REQ_DELETE);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, key)?;
+                body.put_u8(*forwarded as u8);
+            },
+            Request::FindSuccessor { virtual_node_id, key, path } => {
+                body.put_u8(REQ_FIND_SUCCESSOR);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, key)?;
+                body.put_u32(path.len() as u32);
+                for location in path {
+                    put_location(&mut body, location)?;
+                }
+            },
+            Request::Get { virtual_node_id, key, forwarded } => {
+                body.put_u8(REQ_GET);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, key)?;
+                body.put_u8(*forwarded as u8);
+            },
+            Request::GetPredecessor { virtual_node_id } => {
+                body.put_u8(REQ_GET_PREDECESSOR);
+                body.put_u8(*virtual_node_id);
+            },
+            Request::GetSuccessor { virtual_node_id } => {
+                body.put_u8(REQ_GET_SUCCESSOR);
+                body.put_u8(*virtual_node_id);
+            },
+            Request::GetSuccessorList { virtual_node_id } => {
+                body.put_u8(REQ_GET_SUCCESSOR_LIST);
+                body.put_u8(*virtual_node_id);
+            },
+            Request::Handoff { virtual_node_id, entries } => {
+                body.put_u8(REQ_HANDOFF);
+                body.put_u8(*virtual_node_id);
+                put_entries(&mut body, entries)?;
+            },
+            Request::Hello { virtual_node_id, hello } => {
+                body.put_u8(REQ_HELLO);
+                body.put_u8(*virtual_node_id);
+                put_hello(&mut body, hello);
+            },
+            Request::Info { virtual_node_id, json } => {
+                body.put_u8(REQ_INFO);
+                body.put_u8(*virtual_node_id);
+                body.put_u8(*json as u8);
+            },
+            Request::Join { virtual_node_id, location } => {
+                body.put_u8(REQ_JOIN);
+                body.put_u8(*virtual_node_id);
+                put_location(&mut body, location)?;
+            },
+            Request::Leave { virtual_node_id } => {
+                body.put_u8(REQ_LEAVE);
+                body.put_u8(*virtual_node_id);
+            },
+            Request::Lookup { virtual_node_id, key, mode } => {
+                body.put_u8(REQ_LOOKUP);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, key)?;
+                put_option_lookup_mode(&mut body, mode);
+            },
+            Request::LookupKey { virtual_node_id, key } => {
+                body.put_u8(REQ_LOOKUP_KEY);
+                body.put_u8(*virtual_node_id);
+                put_string(&mut body, key);
+            },
+            Request::LookupTrace { virtual_node_id, key } => {
+                body.put_u8(REQ_LOOKUP_TRACE);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, key)?;
+            },
+            Request::Notify { virtual_node_id, notifier } => {
+                body.put_u8(REQ_NOTIFY);
+                body.put_u8(*virtual_node_id);
+                put_location(&mut body, notifier)?;
+            },
+            Request::Ping { virtual_node_id } => {
+                body.put_u8(REQ_PING);
+                body.put_u8(*virtual_node_id);
+            },
+            Request::Put { virtual_node_id, key, value, forwarded } => {
+                body.put_u8(REQ_PUT);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, key)?;
+                put_string(&mut body, value);
+                body.put_u8(*forwarded as u8);
+            },
+            Request::ReplacePredecessor { virtual_node_id, departing, predecessor } => {
+                body.put_u8(REQ_REPLACE_PREDECESSOR);
+                body.put_u8(*virtual_node_id);
+                put_location(&mut body, departing)?;
+                put_option_location(&mut body, predecessor)?;
+            },
+            Request::ReplaceSuccessor { virtual_node_id, departing, successor } => {
+                body.put_u8(REQ_REPLACE_SUCCESSOR);
+                body.put_u8(*virtual_node_id);
+                put_location(&mut body, departing)?;
+                put_location(&mut body, successor)?;
+            },
+            Request::Transfer { virtual_node_id, from, to } => {
+                body.put_u8(REQ_TRANSFER);
+                body.put_u8(*virtual_node_id);
+                put_identifier(&mut body, from)?;
+                put_identifier(&mut body, to)?;
+            },
+        }
+        put_frame(buf, body)
+    }
+
+    /*
+     * Decode a request from a frame, as returned by take_frame().
+     */
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(frame);
+        let tag = reader.get_u8()?;
+        let virtual_node_id = reader.get_u8()?;
+        let request = match tag {
+            REQ_CLOSEST_PRECEDING_FINGER => {
+                let key = reader.get_identifier()?;
+                Request::ClosestPrecedingFinger {
+                    virtual_node_id,
+                    key,
+                }
+            },
+            REQ_CONFIRM_TRANSFER => {
+                let entries = reader.get_entries()?;
+                Request::ConfirmTransfer {
+                    virtual_node_id,
+                    entries,
+                }
+            },
+            REQ_DELETE => {
+                let key = reader.get_identifier()?;
+                let forwarded = reader.get_u8()? != 0;
+                Request::Delete {
+                    virtual_node_id,
+                    key,
+                    forwarded,
+                }
+            },
+            REQ_FIND_SUCCESSOR => {
+                let key = reader.get_identifier()?;
+                let len = reader.get_u32()?;
+                let mut path = Vec::new();
+                for _ in 0..len {
+                    path.push(reader.get_location()?);
+                }
+                Request::FindSuccessor {
+                    virtual_node_id,
+                    key,
+                    path,
+                }
+            },
+            REQ_GET => {
+                let key = reader.get_identifier()?;
+                let forwarded = reader.get_u8()? != 0;
+                Request::Get {
+                    virtual_node_id,
+                    key,
+                    forwarded,
+                }
+            },
+            REQ_GET_PREDECESSOR => {
+                Request::GetPredecessor {
+                    virtual_node_id,
+                }
+            },
+            REQ_GET_SUCCESSOR => {
+                Request::GetSuccessor {
+                    virtual_node_id,
+                }
+            },
+            REQ_GET_SUCCESSOR_LIST => {
+                Request::GetSuccessorList {
+                    virtual_node_id,
+                }
+            },
+            REQ_HANDOFF => {
+                let entries = reader.get_entries()?;
+                Request::Handoff {
+                    virtual_node_id,
+                    entries,
+                }
+            },
+            REQ_HELLO => {
+                let hello = reader.get_hello()?;
+                Request::Hello {
+                    virtual_node_id,
+                    hello,
+                }
+            },
+            REQ_INFO => {
+                let json = reader.get_u8()? != 0;
+                Request::Info {
+                    virtual_node_id,
+                    json,
+                }
+            },
+            REQ_JOIN => {
+                let location = reader.get_location()?;
+                Request::Join {
+                    virtual_node_id,
+                    location,
+                }
+            },
+            REQ_LEAVE => {
+                Request::Leave {
+                    virtual_node_id,
+                }
+            },
+            REQ_LOOKUP => {
+                let key = reader.get_identifier()?;
+                let mode = reader.get_option_lookup_mode()?;
+                Request::Lookup {
+                    virtual_node_id,
+                    key,
+                    mode,
+                }
+            },
+            REQ_LOOKUP_KEY => {
+                let key = reader.get_string()?;
+                Request::LookupKey {
+                    virtual_node_id,
+                    key,
+                }
+            },
+            REQ_LOOKUP_TRACE => {
+                let key = reader.get_identifier()?;
+                Request::LookupTrace {
+                    virtual_node_id,
+                    key,
+                }
+            },
+            REQ_NOTIFY => {
+                let notifier = reader.get_location()?;
+                Request::Notify {
+                    virtual_node_id,
+                    notifier,
+                }
+            },
+            REQ_PING => {
+                Request::Ping {
+                    virtual_node_id,
+                }
+            },
+            REQ_PUT => {
+                let key = reader.get_identifier()?;
+                let value = reader.get_string()?;
+                let forwarded = reader.get_u8()? != 0;
+                Request::Put {
+                    virtual_node_id,
+                    key,
+                    value,
+                    forwarded,
+                }
+            },
+            REQ_REPLACE_PREDECESSOR => {
+                let departing = reader.get_location()?;
+                let predecessor = reader.get_option_location()?;
+                Request::ReplacePredecessor {
+                    virtual_node_id,
+                    departing,
+                    predecessor,
+                }
+            },
+            REQ_REPLACE_SUCCESSOR => {
+                let departing = reader.get_location()?;
+                let successor = reader.get_location()?;
+                Request::ReplaceSuccessor {
+                    virtual_node_id,
+                    departing,
+                    successor,
+                }
+            },
+            REQ_TRANSFER => {
+                let from = reader.get_identifier()?;
+                let to = reader.get_identifier()?;
+                Request::Transfer {
+                    virtual_node_id,
+                    from,
+                    to,
+                }
+            },
+            _ => {
+                return Err(
+                    format!("Invalid frame. Unrecognized request type tag {}.", tag)
+                    .into());
+            }
+        };
+        reader.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    /*
+     * Encode the response as a frame, appended to buf.
+     */
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<()> {
+        let mut body = BytesMut::new();
+        match self {
+            Response::ClosestPrecedingFinger { location } => {
+                body.put_u8(RES_CLOSEST_PRECEDING_FINGER);
+                put_location(&mut body, location)?;
+            },
+            Response::ConfirmTransfer => {
+                body.put_u8(RES_CONFIRM_TRANSFER);
+            },
+            Response::Delete => {
+                body.put_u8(RES_DELETE);
+            },
+            Response::FindSuccessor { location } => {
+                body.put_u8(RES_FIND_SUCCESSOR);
+                put_location(&mut body, location)?;
+            },
+            Response::Get { value } => {
+                body.put_u8(RES_GET);
+                match value {
+                    Some(value) => {
+                        body.put_u8(1);
+                        put_string(&mut body, value);
+                    },
+                    None => {
+                        body.put_u8(0);
+                    }
+                }
+            },
+            Response::GetPredecessor { location } => {
+                body.put_u8(RES_GET_PREDECESSOR);
+                put_option_location(&mut body, location)?;
+            },
+            Response::GetSuccessor { location } => {
+                body.put_u8(RES_GET_SUCCESSOR);
+                put_location(&mut body, location)?;
+            },
+            Response::GetSuccessorList { locations } => {
+                body.put_u8(RES_GET_SUCCESSOR_LIST);
+                body.put_u32(locations.len() as u32);
+                for location in locations {
+                    put_location(&mut body, location)?;
+                }
+            },
+            Response::Handoff => {
+                body.put_u8(RES_HANDOFF);
+            },
+            Response::Hello { hello } => {
+                body.put_u8(RES_HELLO);
+                put_hello(&mut body, hello);
+            },
+            Response::Info { info } => {
+                body.put_u8(RES_INFO);
+                put_string(&mut body, info);
+            },
+            Response::Join => {
+                body.put_u8(RES_JOIN);
+            },
+            Response::Leave => {
+                body.put_u8(RES_LEAVE);
+            },
+            Response::Lookup { location } => {
+                body.put_u8(RES_LOOKUP);
+                put_location(&mut body, location)?;
+            },
+            Response::LookupKey { identifier, location } => {
+                body.put_u8(RES_LOOKUP_KEY);
+                put_identifier(&mut body, identifier)?;
+                put_location(&mut body, location)?;
+            },
+            Response::LookupTrace { location, hops } => {
+                body.put_u8(RES_LOOKUP_TRACE);
+                put_location(&mut body, location)?;
+                body.put_u32(hops.len() as u32);
+                for hop in hops {
+                    put_location(&mut body, &hop.location)?;
+                    body.put_u64(hop.rtt.as_micros() as u64);
+                }
+            },
+            Response::Notify { accepted } => {
+                body.put_u8(RES_NOTIFY);
+                body.put_u8(*accepted as u8);
+            },
+            Response::Ping => {
+                body.put_u8(RES_PING);
+            },
+            Response::Put => {
+                body.put_u8(RES_PUT);
+            },
+            Response::ReplacePredecessor => {
+                body.put_u8(RES_REPLACE_PREDECESSOR);
+            },
+            Response::ReplaceSuccessor => {
+                body.put_u8(RES_REPLACE_SUCCESSOR);
+            },
+            Response::Transfer { entries } => {
+                body.put_u8(RES_TRANSFER);
+                put_entries(&mut body, entries)?;
+            },
+        }
+        put_frame(buf, body)
+    }
+
+    /*
+     * Decode a response from a frame, as returned by take_frame().
+     * An error frame is decoded as the SicilyError it holds.
+     */
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(frame);
+        let tag = reader.get_u8()?;
+        let response = match tag {
+            RES_ERROR => {
+                let code = reader.get_string()?;
+                let message = reader.get_string()?;
+                reader.finish()?;
+                return Err(SicilyError::from_code(&code, message));
+            },
+            RES_CLOSEST_PRECEDING_FINGER => {
+                let location = reader.get_location()?;
+                Response::ClosestPrecedingFinger {
+                    location,
+                }
+            },
+            RES_CONFIRM_TRANSFER => Response::ConfirmTransfer,
+            RES_DELETE => Response::Delete,
+            RES_FIND_SUCCESSOR => {
+                let location = reader.get_location()?;
+                Response::FindSuccessor {
+                    location,
+                }
+            },
+            RES_GET => {
+                let value = match reader.get_u8()? {
+                    0 => None,
+                    _ => Some(reader.get_string()?),
+                };
+                Response::Get {
+                    value,
+                }
+            },
+            RES_GET_PREDECESSOR => {
+                let location = reader.get_option_location()?;
+                Response::GetPredecessor {
+                    location,
+                }
+            },
+            RES_GET_SUCCESSOR => {
+                let location = reader.get_location()?;
+                Response::GetSuccessor {
+                    location,
+                }
+            },
+            RES_GET_SUCCESSOR_LIST => {
+                let len = reader.get_u32()?;
+                let mut locations = Vec::new();
+                for _ in 0..len {
+                    locations.push(reader.get_location()?);
+                }
+                Response::GetSuccessorList {
+                    locations,
+                }
+            },
+            RES_HANDOFF => Response::Handoff,
+            RES_HELLO => {
+                let hello = reader.get_hello()?;
+                Response::Hello {
+                    hello,
+                }
+            },
+            RES_INFO => {
+                let info = reader.get_string()?;
+                Response::Info {
+                    info,
+                }
+            },
+            RES_JOIN => Response::Join,
+            RES_LEAVE => Response::Leave,
+            RES_LOOKUP => {
+                let location = reader.get_location()?;
+                Response::Lookup {
+                    location,
+                }
+            },
+            RES_LOOKUP_KEY => {
+                let identifier = reader.get_identifier()?;
+                let location = reader.get_location()?;
+                Response::LookupKey {
+                    identifier,
+                    location,
+                }
+            },
+            RES_LOOKUP_TRACE => {
+                let location = reader.get_location()?;
+                let len = reader.get_u32()?;
+                let mut hops = Vec::new();
+                for _ in 0..len {
+                    let location = reader.get_location()?;
+                    let rtt = Duration::from_micros(reader.get_u64()?);
+                    hops.push(Hop {
+                        location,
+                        rtt,
+                    });
+                }
+                Response::LookupTrace {
+                    location,
+                    hops,
+                }
+            },
+            RES_NOTIFY => {
+                let accepted = reader.get_u8()? != 0;
+                Response::Notify {
+                    accepted,
+                }
+            },
+            RES_PING => Response::Ping,
+            RES_PUT => Response::Put,
+            RES_REPLACE_PREDECESSOR => Response::ReplacePredecessor,
+            RES_REPLACE_SUCCESSOR => Response::ReplaceSuccessor,
+            RES_TRANSFER => {
+                let entries = reader.get_entries()?;
+                Response::Transfer {
+                    entries,
+                }
+            },
+            _ => {
+                return Err(
+                    format!("Invalid frame. Unrecognized response type tag {}.", tag)
+                    .into());
+            }
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
+
+impl SicilyError {
+    /*
+     * Encode the error as an error frame, appended to buf.
+     */
+    pub fn encode(&self, buf: &mut BytesMut) -> Result<()> {
+        let mut body = BytesMut::new();
+        body.put_u8(RES_ERROR);
+        put_string(&mut body, self.code());
+        put_string(&mut body, self.message());
+        put_frame(buf, body)
+    }
+}
+
+/*
+ * Whether a frame, as returned by take_frame(), holds a HELLO request.
+ */
+pub fn is_hello(frame: &[u8]) -> bool {
+    frame.first() == Some(&REQ_HELLO)
+}
+
+/*
+ * Prepend the length to a frame body and append the frame to buf.
+ */
+fn put_frame(buf: &mut BytesMut, body: BytesMut) -> Result<()> {
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(
+            format!("Invalid frame. Frame length {} is out of range.", body.len())
+            .into());
+    }
+    buf.reserve(LENGTH_BYTES + body.len());
+    buf.put_u32(body.len() as u32);
+    buf.put_slice(&body);
+    Ok(())
+}
+
+fn put_identifier(buf: &mut BytesMut, identifier: &BigUint) -> Result<()> {
+    let bytes = identifier.to_bytes_be();
+    if bytes.len() > IDENTIFIER_BYTES {
+        return Err(
+            "Invalid identifier. Identifier is too large to be encoded."
+            .into());
+    }
+    buf.put_bytes(0, IDENTIFIER_BYTES - bytes.len());
+    buf.put_slice(&bytes);
+    Ok(())
+}
+
+fn put_string(buf: &mut BytesMut, string: &str) {
+    buf.put_u32(string.len() as u32);
+    buf.put_slice(string.as_bytes());
+}
+
+fn put_location(buf: &mut BytesMut, location: &Location) -> Result<()> {
+    if location.ip.len() > u8::MAX as usize {
+        return Err(
+            "Invalid location. Host is too long to be encoded."
+            .into());
+    }
+    buf.put_u8(location.ip.len() as u8);
+    buf.put_slice(location.ip.as_bytes());
+    buf.put_u16(location.port);
+    buf.put_u8(location.virtual_node_id);
+    put_identifier(buf, &location.identifier)
+}
+
+fn put_option_location(buf: &mut BytesMut, location: &Option<Location>) -> Result<()> {
+    match location {
+        Some(location) => {
+            buf.put_u8(1);
+            put_location(buf, location)
+        },
+        None => {
+            buf.put_u8(0);
+            Ok(())
+        }
+    }
+}
+
+fn put_option_lookup_mode(buf: &mut BytesMut, mode: &Option<LookupMode>) {
+    match mode {
+        Some(mode) => {
+            buf.put_u8(1);
+            match mode {
+                LookupMode::Iterative => buf.put_u8(0),
+                LookupMode::Recursive => buf.put_u8(1),
+            }
+        },
+        None => {
+            buf.put_u8(0);
+        }
+    }
+}
+
+fn put_hello(buf: &mut BytesMut, hello: &Hello) {
+    buf.put_u16(hello.protocol_version);
+    put_string(buf, &hello.cluster_name);
+    buf.put_u8(hello.id_bits);
+    put_string(buf, &hello.hash);
+}
+
+fn put_entries(buf: &mut BytesMut, entries: &[(BigUint, String)]) -> Result<()> {
+    buf.put_u32(entries.len() as u32);
+    for (key, value) in entries {
+        put_identifier(buf, key)?;
+        put_string(buf, value);
+    }
+    Ok(())
+}
+
+/*
+ * Cursor over a frame, with every read checked against the frame length.
+ */
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+        }
+    }
+
+    fn get_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err("Invalid frame. Frame ended unexpectedly.".into());
+        }
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> Result<u16> {
+        let bytes = self.get_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn get_u32(&mut self) -> Result<u32> {
+        let bytes = self.get_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn get_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.get_bytes(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn get_identifier(&mut self) -> Result<BigUint> {
+        Ok(BigUint::from_bytes_be(self.get_bytes(IDENTIFIER_BYTES)?))
+    }
+
+    fn get_string(&mut self) -> Result<String> {
+        let len = self.get_u32()? as usize;
+        let bytes = self.get_bytes(len)?;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    fn get_location(&mut self) -> Result<Location> {
+        let len = self.get_u8()? as usize;
+        let ip = String::from_utf8(self.get_bytes(len)?.to_vec())?;
+        let port = self.get_u16()?;
+        let virtual_node_id = self.get_u8()?;
+        let identifier = self.get_identifier()?;
+        Ok(Location {
+            ip,
+            port,
+            virtual_node_id,
+            identifier,
+        })
+    }
+
+    fn get_option_location(&mut self) -> Result<Option<Location>> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.get_location()?)),
+        }
+    }
+
+    fn get_option_lookup_mode(&mut self) -> Result<Option<LookupMode>> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            _ => match self.get_u8()? {
+                0 => Ok(Some(LookupMode::Iterative)),
+                1 => Ok(Some(LookupMode::Recursive)),
+                mode => Err(format!("Invalid frame. Unknown lookup mode {}.", mode).into()),
+            },
+        }
+    }
+
+    fn get_hello(&mut self) -> Result<Hello> {
+        let protocol_version = self.get_u16()?;
+        let cluster_name = self.get_string()?;
+        let id_bits = self.get_u8()?;
+        let hash = self.get_string()?;
+        Ok(Hello {
+            protocol_version,
+            cluster_name,
+            id_bits,
+            hash,
+        })
+    }
+
+    fn get_entries(&mut self) -> Result<Vec<(BigUint, String)>> {
+        let len = self.get_u32()?;
+        let mut entries = Vec::new();
+        for _ in 0..len {
+            let key = self.get_identifier()?;
+            let value = self.get_string()?;
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    /*
+     * Make sure the whole frame has been consumed.
+     */
+    fn finish(&self) -> Result<()> {
+        if !self.buf.is_empty() {
+            return Err("Invalid frame. Unexpected trailing bytes.".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(ip: &str, port: u16, virtual_node_id: u8, identifier: u64) -> Location {
+        Location {
+            ip: ip.to_string(),
+            port,
+            virtual_node_id,
+            identifier: BigUint::from(identifier),
+        }
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = Request::ReplacePredecessor {
+            virtual_node_id: 3,
+            departing: location("10.0.0.1", 8820, 1, 42),
+            predecessor: Some(location("10.0.0.2", 8821, 7, 4242)),
+        };
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf).unwrap();
+        let frame = take_frame(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        match Request::decode(&frame).unwrap() {
+            Request::ReplacePredecessor { virtual_node_id, departing, predecessor } => {
+                assert_eq!(virtual_node_id, 3);
+                assert_eq!(departing, location("10.0.0.1", 8820, 1, 42));
+                assert_eq!(predecessor, Some(location("10.0.0.2", 8821, 7, 4242)));
+            },
+            other => panic!("Unexpected request {:?}", other),
+        }
+
+        let path = vec![location("10.0.0.1", 8820, 1, 42), location("10.0.0.2", 8821, 7, 4242)];
+        let request = Request::FindSuccessor {
+            virtual_node_id: 2,
+            key: BigUint::from(4000u32),
+            path: path.clone(),
+        };
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf).unwrap();
+        let frame = take_frame(&mut buf).unwrap().unwrap();
+        match Request::decode(&frame).unwrap() {
+            Request::FindSuccessor { virtual_node_id, key, path: decoded } => {
+                assert_eq!(virtual_node_id, 2);
+                assert_eq!(key, BigUint::from(4000u32));
+                assert_eq!(decoded, path);
+            },
+            other => panic!("Unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let entries = vec![
+            (BigUint::from(1u32), "one".to_string()),
+            (BigUint::from(2u32), "two words".to_string()),
+        ];
+        let response = Response::Transfer { entries: entries.clone() };
+        let mut buf = BytesMut::new();
+        response.encode(&mut buf).unwrap();
+        let frame = take_frame(&mut buf).unwrap().unwrap();
+        match Response::decode(&frame).unwrap() {
+            Response::Transfer { entries: decoded } => assert_eq!(decoded, entries),
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hello_round_trip() {
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_name: "staging".to_string(),
+            id_bits: 64,
+            hash: "sha1".to_string(),
+        };
+        let request = Request::Hello { virtual_node_id: 2, hello: hello.clone() };
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf).unwrap();
+        let frame = take_frame(&mut buf).unwrap().unwrap();
+        assert!(is_hello(&frame));
+        match Request::decode(&frame).unwrap() {
+            Request::Hello { virtual_node_id, hello: decoded } => {
+                assert_eq!(virtual_node_id, 2);
+                assert_eq!(decoded, hello);
+            },
+            other => panic!("Unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_round_trip() {
+        let errors = vec![
+            SicilyError::Parse("Invalid key.".to_string()),
+            SicilyError::UnknownVirtualNode("Virtual node id 9 does not exist.".to_string()),
+            SicilyError::Timeout("Request timed out.".to_string()),
+        ];
+        for error in errors {
+            let mut buf = BytesMut::new();
+            error.encode(&mut buf).unwrap();
+            let frame = take_frame(&mut buf).unwrap().unwrap();
+            let decoded = Response::decode(&frame).unwrap_err();
+            assert_eq!(decoded, error);
+        }
+    }
+
+    #[test]
+    fn test_lookup_trace_round_trip() {
+        let hops = vec![
+            Hop { location: location("10.0.0.1", 8820, 0, 10), rtt: Duration::from_micros(120) },
+            Hop { location: location("10.0.0.2", 8820, 5, 200), rtt: Duration::from_micros(3400) },
+        ];
+        let response = Response::LookupTrace { location: location("10.0.0.3", 8820, 1, 220), hops: hops.clone() };
+        let mut buf = BytesMut::new();
+        response.encode(&mut buf).unwrap();
+        let frame = take_frame(&mut buf).unwrap().unwrap();
+        match Response::decode(&frame).unwrap() {
+            Response::LookupTrace { location: owner, hops: decoded } => {
+                assert_eq!(owner, location("10.0.0.3", 8820, 1, 220));
+                assert_eq!(decoded, hops);
+            },
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lookup_mode_round_trip() {
+        for mode in [None, Some(LookupMode::Iterative), Some(LookupMode::Recursive)] {
+            let request = Request::Lookup { virtual_node_id: 2, key: BigUint::from(42u32), mode };
+            let mut buf = BytesMut::new();
+            request.encode(&mut buf).unwrap();
+            let frame = take_frame(&mut buf).unwrap().unwrap();
+            match Request::decode(&frame).unwrap() {
+                Request::Lookup { virtual_node_id, key, mode: decoded } => {
+                    assert_eq!(virtual_node_id, 2);
+                    assert_eq!(key, BigUint::from(42u32));
+                    assert_eq!(decoded, mode);
+                },
+                other => panic!("Unexpected request {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_take_frame_partial() {
+        let mut full = BytesMut::new();
+        Request::Ping { virtual_node_id: 0 }.encode(&mut full).unwrap();
+        Request::Info { virtual_node_id: 1, json: false }.encode(&mut full).unwrap();
+
+        /* Feed the frames byte by byte, as if they arrive in small segments. */
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        for byte in full.iter() {
+            buf.put_u8(*byte);
+            while let Some(frame) = take_frame(&mut buf).unwrap() {
+                frames.push(Request::decode(&frame).unwrap());
+            }
+        }
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(frames[0], Request::Ping { virtual_node_id: 0 }));
+        assert!(matches!(frames[1], Request::Info { virtual_node_id: 1, json: false }));
+    }
+
+    #[test]
+    fn test_take_line_pipelined() {
+        let mut buf = BytesMut::from("INFO 0\r\nPING 0\nLOOK");
+        assert_eq!(&take_line(&mut buf).unwrap().unwrap()[..], b"INFO 0\r\n");
+        assert_eq!(&take_line(&mut buf).unwrap().unwrap()[..], b"PING 0\n");
+        assert!(take_line(&mut buf).unwrap().is_none());
+
+        buf.put_slice(b"UP 0 42\r\n");
+        assert_eq!(&take_line(&mut buf).unwrap().unwrap()[..], b"LOOKUP 0 42\r\n");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let mut buf = BytesMut::new();
+        Request::Lookup { virtual_node_id: 0, key: BigUint::from(42u32), mode: None }.encode(&mut buf).unwrap();
+        let frame = take_frame(&mut buf).unwrap().unwrap();
+        assert!(Request::decode(&frame[..frame.len() - 1]).is_err());
+    }
+}
\ No newline at end of file
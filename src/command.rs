@@ -17,48 +17,187 @@
 
 use bytes::BytesMut;
 use num::BigUint;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{ Duration, Instant };
 
-use crate::config::Config;
+use crate::arithmetic;
+use crate::config::{ Config, LookupMode };
+use crate::constants::*;
+use crate::error::SicilyError;
 use crate::location::Location;
 use crate::membership;
 use crate::node::NodeList;
-use crate::process;
+use crate::process::{ self, Hop };
 use crate::utils::Result;
 
+/*
+ * What two nodes must agree on to talk to each other. Nodes say hello when they set up
+ * a connection, and when they join a cluster.
+ * The identifier bits and the hash algorithm matter because every node recomputes the
+ * identifiers of the locations it receives with its own.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub cluster_name: String,
+    pub id_bits: u8,
+    pub hash: String,
+}
+
+impl Hello {
+    /*
+     * The hello of the local node.
+     */
+    pub fn new(config: &Config) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_name: config.cluster_name.clone(),
+            id_bits: config.id_bits,
+            hash: config.hash.name().to_string(),
+        }
+    }
+
+    /*
+     * Parse "<protocol_version> <cluster_name> <id_bits> <hash>".
+     */
+    fn parse(arr: &[&str]) -> Result<Self> {
+        Ok(Self {
+            protocol_version: arr[0].parse::<u16>()?,
+            cluster_name: arr[1].to_string(),
+            id_bits: arr[2].parse::<u8>()?,
+            hash: arr[3].to_string(),
+        })
+    }
+
+    /*
+     * Make sure a peer saying this hello can talk to the local node.
+     */
+    pub fn check(&self, config: &Config) -> Result<()> {
+        let local = Hello::new(config);
+        let mismatch = if self.protocol_version != local.protocol_version {
+            format!("Peer talks protocol version {}, but the local node talks {}.", self.protocol_version, local.protocol_version)
+        } else if self.cluster_name != local.cluster_name {
+            format!("Peer belongs to cluster {}, but the local node belongs to {}.", self.cluster_name, local.cluster_name)
+        } else if self.id_bits != local.id_bits {
+            format!("Peer uses {} identifier bits, but the local node uses {}.", self.id_bits, local.id_bits)
+        } else if self.hash != local.hash {
+            format!("Peer uses hash algorithm {}, but the local node uses {}.", self.hash, local.hash)
+        } else {
+            return Ok(());
+        };
+        Err(SicilyError::Incompatible(mismatch))
+    }
+}
+
+impl fmt::Display for Hello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.protocol_version, self.cluster_name, self.id_bits, self.hash)
+    }
+}
+
 #[derive(Debug)]
 pub enum Request {
     ClosestPrecedingFinger {
         virtual_node_id: u8,
         key: BigUint,
     },
+    ConfirmTransfer {
+        virtual_node_id: u8,
+        entries: Vec<(BigUint, String)>,
+    },
+    Delete {
+        virtual_node_id: u8,
+        key: BigUint,
+        forwarded: bool,
+    },
+    FindSuccessor {
+        virtual_node_id: u8,
+        key: BigUint,
+        path: Vec<Location>,
+    },
+    Get {
+        virtual_node_id: u8,
+        key: BigUint,
+        forwarded: bool,
+    },
     GetPredecessor {
         virtual_node_id: u8,
     },
     GetSuccessor {
         virtual_node_id: u8,
     },
+    GetSuccessorList {
+        virtual_node_id: u8,
+    },
+    Handoff {
+        virtual_node_id: u8,
+        entries: Vec<(BigUint, String)>,
+    },
+    Hello {
+        virtual_node_id: u8,
+        hello: Hello,
+    },
     Info {
         virtual_node_id: u8,
+        json: bool,
     },
     Join {
         virtual_node_id: u8,
         location: Location,
     },
+    Leave {
+        virtual_node_id: u8,
+    },
     Lookup {
         virtual_node_id: u8,
         key: BigUint,
+        mode: Option<LookupMode>,
+    },
+    LookupKey {
+        virtual_node_id: u8,
+        key: String,
+    },
+    LookupTrace {
+        virtual_node_id: u8,
+        key: BigUint,
     },
     Notify {
         virtual_node_id: u8,
         notifier: Location,
-    }
+    },
+    Ping {
+        virtual_node_id: u8,
+    },
+    Put {
+        virtual_node_id: u8,
+        key: BigUint,
+        value: String,
+        forwarded: bool,
+    },
+    ReplacePredecessor {
+        virtual_node_id: u8,
+        departing: Location,
+        predecessor: Option<Location>,
+    },
+    ReplaceSuccessor {
+        virtual_node_id: u8,
+        departing: Location,
+        successor: Location,
+    },
+    Transfer {
+        virtual_node_id: u8,
+        from: BigUint,
+        to: BigUint,
+    },
 }
 
 impl Request {
 
     /*
-     * Parse request from buffer.
+     * Parse request from a line of the text protocol.
      * Return parsed request, and whether this request is initiated by a human client,
      * which ends with "\r\n".
      * 
@@ -72,12 +211,15 @@ impl Request {
         let mut s = String::from_utf8(buf.to_vec())?;
 
         let mut is_human_client = false;
-        /* Remove trailing "\r\n" if the command if input by user. */
+        /* Remove trailing "\r\n" if the command if input by user, or the trailing "\n" otherwise. */
         if s.ends_with("\r\n") {
             is_human_client = true;
             s = s[0..s.len()-2].to_string();
+        } else if s.ends_with('\n') {
+            s = s[0..s.len()-1].to_string();
         }
-        let arr: Vec<&str> = s.split(" ").collect();
+        let words = split_words(&s)?;
+        let arr: Vec<&str> = words.iter().map(String::as_str).collect();
 
         /* Should have at least one valid string in the array vector after split. */
         if arr.len() <= 1 {
@@ -97,6 +239,56 @@ impl Request {
                     key,
                 }
             },
+            "confirmtransfer" => {
+                /* Entries come in pairs of key and value. */
+                if !arr.len().is_multiple_of(2) {
+                    return Err(
+                        "Invalid command. CONFIRMTRANSFER command takes pairs of key and value."
+                        .into());
+                }
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let entries = parse_entries(&arr[2..])?;
+                Request::ConfirmTransfer {
+                    virtual_node_id,
+                    entries,
+                }
+            },
+            "delete" => {
+                let forwarded = parse_forwarded(&arr, 3)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let key = parse_key(arr[2])?;
+                Request::Delete {
+                    virtual_node_id,
+                    key,
+                    forwarded,
+                }
+            },
+            "findsuccessor" => {
+                if arr.len() < 3 {
+                    check_params_len(&arr, 3)?;
+                }
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let key = parse_key(arr[2])?;
+                let mut path = Vec::new();
+                for location in arr[3..].iter() {
+                    path.push(Location::from_string(location.to_string(), config.clone())?);
+                }
+                Request::FindSuccessor {
+                    virtual_node_id,
+                    key,
+                    path,
+                }
+            },
+            "get" => {
+                let forwarded = parse_forwarded(&arr, 3)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let key = parse_key(arr[2])?;
+                Request::Get {
+                    virtual_node_id,
+                    key,
+                    forwarded,
+                }
+            },
             "getpredecessor" => {
                 check_params_len(&arr, 2)?;
                 let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
@@ -111,11 +303,54 @@ impl Request {
                     virtual_node_id,
                 }
             },
-            "info" => {
+            "getsuccessorlist" => {
                 check_params_len(&arr, 2)?;
                 let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                Request::GetSuccessorList {
+                    virtual_node_id,
+                }
+            },
+            "handoff" => {
+                /* Entries come in pairs of key and value. */
+                if !arr.len().is_multiple_of(2) {
+                    return Err(
+                        "Invalid command. HANDOFF command takes pairs of key and value."
+                        .into());
+                }
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let entries = parse_entries(&arr[2..])?;
+                Request::Handoff {
+                    virtual_node_id,
+                    entries,
+                }
+            },
+            "hello" => {
+                check_params_len(&arr, 6)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let hello = Hello::parse(&arr[2..])?;
+                Request::Hello {
+                    virtual_node_id,
+                    hello,
+                }
+            },
+            "info" => {
+                /* The JSON format is optional. */
+                let json = match arr.len() {
+                    2 => false,
+                    _ => {
+                        check_params_len(&arr, 3)?;
+                        if arr[2].to_lowercase() != "json" {
+                            return Err(
+                                "Invalid command. INFO command only supports \"JSON\" as format."
+                                .into());
+                        }
+                        true
+                    }
+                };
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
                 Request::Info {
                     virtual_node_id,
+                    json,
                 }
             },
             "join" => {
@@ -127,11 +362,49 @@ impl Request {
                     location,
                 }
             },
+            "leave" => {
+                check_params_len(&arr, 2)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                Request::Leave {
+                    virtual_node_id,
+                }
+            },
             "lookup" => {
-                check_params_len(&arr, 3)?;
+                /* The last parameter is optional. It is either the lookup mode, or TRACE.
+                 * The configured lookup mode is used if it's absent. */
+                if arr.len() != 3 {
+                    check_params_len(&arr, 4)?;
+                }
                 let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
                 let key = parse_key(arr[2])?;
-                Request::Lookup {
+                match arr.get(3) {
+                    Some(param) if param.to_lowercase() == "trace" => {
+                        Request::LookupTrace {
+                            virtual_node_id,
+                            key,
+                        }
+                    },
+                    Some(param) => {
+                        Request::Lookup {
+                            virtual_node_id,
+                            key,
+                            mode: Some(LookupMode::parse(param)?),
+                        }
+                    },
+                    None => {
+                        Request::Lookup {
+                            virtual_node_id,
+                            key,
+                            mode: None,
+                        }
+                    },
+                }
+            },
+            "lookupkey" => {
+                check_params_len(&arr, 3)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let key = arr[2].to_string();
+                Request::LookupKey {
                     virtual_node_id,
                     key,
                 }
@@ -145,6 +418,61 @@ impl Request {
                     notifier,
                 }
             },
+            "ping" => {
+                check_params_len(&arr, 2)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                Request::Ping {
+                    virtual_node_id,
+                }
+            },
+            "put" => {
+                let forwarded = parse_forwarded(&arr, 4)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let key = parse_key(arr[2])?;
+                let value = arr[3].to_string();
+                Request::Put {
+                    virtual_node_id,
+                    key,
+                    value,
+                    forwarded,
+                }
+            },
+            "replacepredecessor" => {
+                check_params_len(&arr, 4)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let departing = Location::from_string(arr[2].to_string(), config.clone())?;
+                let predecessor = match arr[3].to_lowercase().as_str() {
+                    "none" => None,
+                    _ => Some(Location::from_string(arr[3].to_string(), config)?),
+                };
+                Request::ReplacePredecessor {
+                    virtual_node_id,
+                    departing,
+                    predecessor,
+                }
+            },
+            "replacesuccessor" => {
+                check_params_len(&arr, 4)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let departing = Location::from_string(arr[2].to_string(), config.clone())?;
+                let successor = Location::from_string(arr[3].to_string(), config)?;
+                Request::ReplaceSuccessor {
+                    virtual_node_id,
+                    departing,
+                    successor,
+                }
+            },
+            "transfer" => {
+                check_params_len(&arr, 4)?;
+                let virtual_node_id = parse_virtual_node_id(arr[1], node_list.clone())?;
+                let from = parse_key(arr[2])?;
+                let to = parse_key(arr[3])?;
+                Request::Transfer {
+                    virtual_node_id,
+                    from,
+                    to,
+                }
+            },
             _ => {
                 return Err(
                     "Invalid command. Unrecognized command."
@@ -159,27 +487,218 @@ impl Request {
             Request::ClosestPrecedingFinger { virtual_node_id, key } => {
                 format!("CLOSESTPRECEDINGFINGER {} {}", virtual_node_id, key)
             },
+            Request::ConfirmTransfer { virtual_node_id, entries } => {
+                let mut res = format!("CONFIRMTRANSFER {}", virtual_node_id);
+                for (key, value) in entries {
+                    res.push_str(&format!(" {} {}", key, quote_value(value)));
+                }
+                res
+            },
+            Request::Delete { virtual_node_id, key, forwarded } => {
+                format!("DELETE {} {}{}", virtual_node_id, key, forwarded_suffix(*forwarded))
+            },
+            Request::FindSuccessor { virtual_node_id, key, path } => {
+                let mut res = format!("FINDSUCCESSOR {} {}", virtual_node_id, key);
+                for location in path {
+                    res.push_str(&format!(" {}", location));
+                }
+                res
+            },
+            Request::Get { virtual_node_id, key, forwarded } => {
+                format!("GET {} {}{}", virtual_node_id, key, forwarded_suffix(*forwarded))
+            },
             Request::GetPredecessor { virtual_node_id } => {
                 format!("GETPREDECESSOR {}", virtual_node_id)
             },
             Request::GetSuccessor { virtual_node_id } => {
                 format!("GETSUCCESSOR {}", virtual_node_id)
             },
-            Request::Info { virtual_node_id } => {
-                format!("INFO {}", virtual_node_id)
+            Request::GetSuccessorList { virtual_node_id } => {
+                format!("GETSUCCESSORLIST {}", virtual_node_id)
+            },
+            Request::Handoff { virtual_node_id, entries } => {
+                let mut res = format!("HANDOFF {}", virtual_node_id);
+                for (key, value) in entries {
+                    res.push_str(&format!(" {} {}", key, quote_value(value)));
+                }
+                res
+            },
+            Request::Hello { virtual_node_id, hello } => {
+                format!("HELLO {} {}", virtual_node_id, hello)
+            },
+            Request::Info { virtual_node_id, json } => {
+                match json {
+                    true => format!("INFO {} JSON", virtual_node_id),
+                    false => format!("INFO {}", virtual_node_id),
+                }
             },
             Request::Join { virtual_node_id, location } => {
-                format!("JOIN {} {}", virtual_node_id, location.to_string())
+                format!("JOIN {} {}", virtual_node_id, location)
             },
-            Request::Lookup { virtual_node_id, key } => {
-                format!("LOOKUP {} {}", virtual_node_id, key)
+            Request::Leave { virtual_node_id } => {
+                format!("LEAVE {}", virtual_node_id)
+            },
+            Request::Lookup { virtual_node_id, key, mode } => {
+                match mode {
+                    Some(mode) => format!("LOOKUP {} {} {}", virtual_node_id, key, mode),
+                    None => format!("LOOKUP {} {}", virtual_node_id, key),
+                }
+            },
+            Request::LookupKey { virtual_node_id, key } => {
+                format!("LOOKUPKEY {} {}", virtual_node_id, key)
+            },
+            Request::LookupTrace { virtual_node_id, key } => {
+                format!("LOOKUP {} {} TRACE", virtual_node_id, key)
             },
             Request::Notify { virtual_node_id, notifier } => {
-                format!("NOTIFY {} {}", virtual_node_id, notifier.to_string())
+                format!("NOTIFY {} {}", virtual_node_id, notifier)
+            },
+            Request::Ping { virtual_node_id } => {
+                format!("PING {}", virtual_node_id)
+            },
+            Request::Put { virtual_node_id, key, value, forwarded } => {
+                format!("PUT {} {} {}{}", virtual_node_id, key, quote_value(value), forwarded_suffix(*forwarded))
+            },
+            Request::ReplacePredecessor { virtual_node_id, departing, predecessor } => {
+                match predecessor {
+                    Some(predecessor) => format!("REPLACEPREDECESSOR {} {} {}", virtual_node_id, departing, predecessor),
+                    None => format!("REPLACEPREDECESSOR {} {} NONE", virtual_node_id, departing),
+                }
+            },
+            Request::ReplaceSuccessor { virtual_node_id, departing, successor } => {
+                format!("REPLACESUCCESSOR {} {} {}", virtual_node_id, departing, successor)
+            },
+            Request::Transfer { virtual_node_id, from, to } => {
+                format!("TRANSFER {} {} {}", virtual_node_id, from, to)
             },
         };
         Ok(res)
     }
+
+    /*
+     * Get the virtual node id the request is addressed to.
+     */
+    pub fn virtual_node_id(&self) -> u8 {
+        match self {
+            Request::ClosestPrecedingFinger { virtual_node_id, .. } => *virtual_node_id,
+            Request::ConfirmTransfer { virtual_node_id, .. } => *virtual_node_id,
+            Request::Delete { virtual_node_id, .. } => *virtual_node_id,
+            Request::FindSuccessor { virtual_node_id, .. } => *virtual_node_id,
+            Request::Get { virtual_node_id, .. } => *virtual_node_id,
+            Request::GetPredecessor { virtual_node_id } => *virtual_node_id,
+            Request::GetSuccessor { virtual_node_id } => *virtual_node_id,
+            Request::GetSuccessorList { virtual_node_id } => *virtual_node_id,
+            Request::Handoff { virtual_node_id, .. } => *virtual_node_id,
+            Request::Hello { virtual_node_id, .. } => *virtual_node_id,
+            Request::Info { virtual_node_id, .. } => *virtual_node_id,
+            Request::Join { virtual_node_id, .. } => *virtual_node_id,
+            Request::Leave { virtual_node_id } => *virtual_node_id,
+            Request::Lookup { virtual_node_id, .. } => *virtual_node_id,
+            Request::LookupKey { virtual_node_id, .. } => *virtual_node_id,
+            Request::LookupTrace { virtual_node_id, .. } => *virtual_node_id,
+            Request::Notify { virtual_node_id, .. } => *virtual_node_id,
+            Request::Ping { virtual_node_id } => *virtual_node_id,
+            Request::Put { virtual_node_id, .. } => *virtual_node_id,
+            Request::ReplacePredecessor { virtual_node_id, .. } => *virtual_node_id,
+            Request::ReplaceSuccessor { virtual_node_id, .. } => *virtual_node_id,
+            Request::Transfer { virtual_node_id, .. } => *virtual_node_id,
+        }
+    }
+
+    /*
+     * Get the name of the request, which is its command in lowercase.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::ClosestPrecedingFinger { .. } => "closestprecedingfinger",
+            Request::ConfirmTransfer { .. } => "confirmtransfer",
+            Request::Delete { .. } => "delete",
+            Request::FindSuccessor { .. } => "findsuccessor",
+            Request::Get { .. } => "get",
+            Request::GetPredecessor { .. } => "getpredecessor",
+            Request::GetSuccessor { .. } => "getsuccessor",
+            Request::GetSuccessorList { .. } => "getsuccessorlist",
+            Request::Handoff { .. } => "handoff",
+            Request::Hello { .. } => "hello",
+            Request::Info { .. } => "info",
+            Request::Join { .. } => "join",
+            Request::Leave { .. } => "leave",
+            Request::Lookup { .. } => "lookup",
+            Request::LookupKey { .. } => "lookupkey",
+            Request::LookupTrace { .. } => "lookuptrace",
+            Request::Notify { .. } => "notify",
+            Request::Ping { .. } => "ping",
+            Request::Put { .. } => "put",
+            Request::ReplacePredecessor { .. } => "replacepredecessor",
+            Request::ReplaceSuccessor { .. } => "replacesuccessor",
+            Request::Transfer { .. } => "transfer",
+        }
+    }
+
+    /*
+     * Whether the request is only sent between nodes. It changes the routing state or
+     * the store of the node bypassing the usual checks, so it is only accepted on binary
+     * connections, which have said hello first, and never on the text protocol.
+     * NOTIFY stays available on the text protocol, like JOIN: the node only adopts the
+     * notifier if it lies between its predecessor and itself, as during stabilization.
+     */
+    pub fn is_internal(&self) -> bool {
+        match self {
+            Request::ClosestPrecedingFinger { .. } => false,
+            Request::ConfirmTransfer { .. } => true,
+            Request::Delete { forwarded, .. } => *forwarded,
+            Request::FindSuccessor { .. } => false,
+            Request::Get { forwarded, .. } => *forwarded,
+            Request::GetPredecessor { .. } => false,
+            Request::GetSuccessor { .. } => false,
+            Request::GetSuccessorList { .. } => false,
+            Request::Handoff { .. } => true,
+            Request::Hello { .. } => false,
+            Request::Info { .. } => false,
+            Request::Join { .. } => false,
+            Request::Leave { .. } => false,
+            Request::Lookup { .. } => false,
+            Request::LookupKey { .. } => false,
+            Request::LookupTrace { .. } => false,
+            Request::Notify { .. } => false,
+            Request::Ping { .. } => false,
+            Request::Put { forwarded, .. } => *forwarded,
+            Request::ReplacePredecessor { .. } => true,
+            Request::ReplaceSuccessor { .. } => true,
+            Request::Transfer { .. } => true,
+        }
+    }
+
+    /*
+     * Whether the request can safely be sent again when it isn't known whether the
+     * peer got it the first time. Only requests that don't change any state are.
+     */
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::ClosestPrecedingFinger { .. } => true,
+            Request::ConfirmTransfer { .. } => false,
+            Request::Delete { .. } => false,
+            Request::FindSuccessor { .. } => true,
+            Request::Get { .. } => true,
+            Request::GetPredecessor { .. } => true,
+            Request::GetSuccessor { .. } => true,
+            Request::GetSuccessorList { .. } => true,
+            Request::Handoff { .. } => false,
+            Request::Hello { .. } => true,
+            Request::Info { .. } => true,
+            Request::Join { .. } => false,
+            Request::Leave { .. } => false,
+            Request::Lookup { .. } => true,
+            Request::LookupKey { .. } => true,
+            Request::LookupTrace { .. } => true,
+            Request::Notify { .. } => false,
+            Request::Ping { .. } => true,
+            Request::Put { .. } => false,
+            Request::ReplacePredecessor { .. } => false,
+            Request::ReplaceSuccessor { .. } => false,
+            Request::Transfer { .. } => true,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -187,20 +706,53 @@ pub enum Response {
     ClosestPrecedingFinger {
         location: Location,
     },
+    ConfirmTransfer,
+    Delete,
+    FindSuccessor {
+        location: Location,
+    },
+    Get {
+        value: Option<String>,
+    },
     GetPredecessor {
         location: Option<Location>,
     },
     GetSuccessor {
         location: Location,
     },
+    GetSuccessorList {
+        locations: Vec<Location>,
+    },
+    Handoff,
+    Hello {
+        hello: Hello,
+    },
     Info {
         info: String,
     },
     Join,
+    Leave,
     Lookup {
         location: Location,
     },
-    Notify,
+    LookupKey {
+        identifier: BigUint,
+        location: Location,
+    },
+    LookupTrace {
+        location: Location,
+        hops: Vec<Hop>,
+    },
+    Notify {
+        accepted: bool,
+    },
+    Ping,
+    Put,
+    ReplacePredecessor,
+    ReplaceSuccessor,
+    Transfer {
+        entries: Vec<(BigUint, String)>,
+    },
 }
 
 impl Response {
@@ -210,7 +762,9 @@ impl Response {
      */
     pub fn parse_from_buf(buf: &BytesMut, config: Arc<Config>) -> Result<Self> {
         let s = String::from_utf8(buf.to_vec())?;
-        let arr: Vec<&str> = s.split(" ").collect();
+        let s = s.trim_end_matches(['\r', '\n']);
+        let words = split_words(s)?;
+        let arr: Vec<&str> = words.iter().map(String::as_str).collect();
 
         /* Should have at least two valid string in the array vector after split. */
         if arr.len() < 2 {
@@ -219,6 +773,11 @@ impl Response {
                 .into());
         }
 
+        /* A failed request is answered with "ERR <code> <message>". */
+        if arr[0].to_lowercase() == "err" {
+            return Err(SicilyError::from_code(arr[1], arr[2..].join(" ")));
+        }
+
         if arr[0].to_lowercase() != "res" {
             return Err(
                 "Invalid response. The first string is not \"RES\"."
@@ -234,6 +793,38 @@ impl Response {
                     location,
                 }
             },
+            "confirmtransfer" => {
+                check_params_len(&arr, 2)?;
+                Response::ConfirmTransfer
+            },
+            "delete" => {
+                check_params_len(&arr, 2)?;
+                Response::Delete
+            },
+            "findsuccessor" => {
+                check_params_len(&arr, 3)?;
+                let location = Location::from_string(arr[2].to_string(), config)?;
+                Response::FindSuccessor {
+                    location,
+                }
+            },
+            "get" => {
+                if arr.len() == 3 && arr[2].to_lowercase() == "none" {
+                    Response::Get {
+                        value: None,
+                    }
+                } else {
+                    check_params_len(&arr, 4)?;
+                    if arr[2].to_lowercase() != "value" {
+                        return Err(
+                            "Invalid response. GET response should be either \"NONE\" or \"VALUE\"."
+                            .into());
+                    }
+                    Response::Get {
+                        value: Some(arr[3].to_string()),
+                    }
+                }
+            },
             "getpredecessor" => {
                 check_params_len(&arr, 3)?;
                 if arr[2].to_lowercase() == "none" {
@@ -254,12 +845,31 @@ impl Response {
                     location,
                 }
             },
-            "info" => {
-                /* No need to check param number. */
-                let mut info = "".to_string();
-                for i in 2..arr.len() {
-                    info.push_str(arr[i]);
+            "getsuccessorlist" => {
+                /* No need to check param number. The list can be empty. */
+                let mut locations = Vec::new();
+                for piece in arr.iter().skip(2) {
+                    let location = Location::from_string(piece.to_string(), config.clone())?;
+                    locations.push(location);
+                }
+                Response::GetSuccessorList {
+                    locations,
+                }
+            },
+            "handoff" => {
+                check_params_len(&arr, 2)?;
+                Response::Handoff
+            },
+            "hello" => {
+                check_params_len(&arr, 6)?;
+                let hello = Hello::parse(&arr[2..])?;
+                Response::Hello {
+                    hello,
                 }
+            },
+            "info" => {
+                /* No need to check param number. The info may contain spaces itself. */
+                let info = arr[2..].join(" ");
                 Response::Info {
                     info,
                 }
@@ -267,7 +877,11 @@ impl Response {
             "join" => {
                 check_params_len(&arr, 2)?;
                 Response::Join
-            }
+            },
+            "leave" => {
+                check_params_len(&arr, 2)?;
+                Response::Leave
+            },
             "lookup" => {
                 check_params_len(&arr, 3)?;
                 let location = Location::from_string(arr[2].to_string(), config)?;
@@ -275,9 +889,76 @@ impl Response {
                     location,
                 }
             },
+            "lookupkey" => {
+                check_params_len(&arr, 4)?;
+                let identifier = parse_key(arr[2])?;
+                let location = Location::from_string(arr[3].to_string(), config)?;
+                Response::LookupKey {
+                    identifier,
+                    location,
+                }
+            },
+            "lookuptrace" => {
+                /* Hops come in pairs of location and round-trip time in microseconds. */
+                if arr.len() < 3 || !arr.len().is_multiple_of(2) {
+                    return Err(
+                        "Invalid response. LOOKUPTRACE response should contain the owner, and pairs of location and round-trip time."
+                        .into());
+                }
+                let location = Location::from_string(arr[2].to_string(), config.clone())?;
+                let mut hops = Vec::new();
+                for pair in arr[3..].chunks(2) {
+                    let location = Location::from_string(pair[0].to_string(), config.clone())?;
+                    let rtt = Duration::from_micros(str::parse::<u64>(pair[1])?);
+                    hops.push(Hop {
+                        location,
+                        rtt,
+                    });
+                }
+                Response::LookupTrace {
+                    location,
+                    hops,
+                }
+            },
             "notify" => {
+                if arr.len() == 3 && arr[2].to_lowercase() == "accepted" {
+                    Response::Notify {
+                        accepted: true,
+                    }
+                } else {
+                    check_params_len(&arr, 2)?;
+                    Response::Notify {
+                        accepted: false,
+                    }
+                }
+            },
+            "ping" => {
+                check_params_len(&arr, 2)?;
+                Response::Ping
+            },
+            "put" => {
+                check_params_len(&arr, 2)?;
+                Response::Put
+            },
+            "replacepredecessor" => {
+                check_params_len(&arr, 2)?;
+                Response::ReplacePredecessor
+            },
+            "replacesuccessor" => {
                 check_params_len(&arr, 2)?;
-                Response::Notify
+                Response::ReplaceSuccessor
+            },
+            "transfer" => {
+                /* Entries come in pairs of key and value. */
+                if !arr.len().is_multiple_of(2) {
+                    return Err(
+                        "Invalid response. TRANSFER response should contain pairs of key and value."
+                        .into());
+                }
+                let entries = parse_entries(&arr[2..])?;
+                Response::Transfer {
+                    entries,
+                }
             },
             _ => {
                 return Err(
@@ -291,41 +972,122 @@ impl Response {
     pub fn serialize(&self, is_human_client: bool) -> Result<String> {
         let mut res = match self {
             Response::ClosestPrecedingFinger { location } => {
-                format!("RES CLOSESTPRECEDINGFINGER {}", location.to_string())
+                format!("RES CLOSESTPRECEDINGFINGER {}", location)
+            },
+            Response::ConfirmTransfer => {
+                "RES CONFIRMTRANSFER".to_string()
+            },
+            Response::Delete => {
+                "RES DELETE".to_string()
+            },
+            Response::FindSuccessor { location } => {
+                format!("RES FINDSUCCESSOR {}", location)
+            },
+            Response::Get { value } => {
+                match value {
+                    Some(value) => format!("RES GET VALUE {}", quote_value(value)),
+                    None => "RES GET NONE".to_string(),
+                }
             },
             Response::GetPredecessor{ location } => {
                 match location {
-                    Some(location) => format!("RES GETPREDECESSOR {}", location.to_string()),
-                    None => format!("RES GETPREDECESSOR NONE"),
+                    Some(location) => format!("RES GETPREDECESSOR {}", location),
+                    None => "RES GETPREDECESSOR NONE".to_string(),
                 }
             },
             Response::GetSuccessor { location } => {
-                format!("RES GETSUCCESSOR {}", location.to_string())
+                format!("RES GETSUCCESSOR {}", location)
+            },
+            Response::GetSuccessorList { locations } => {
+                let mut res = "RES GETSUCCESSORLIST".to_string();
+                for location in locations {
+                    res.push_str(&format!(" {}", location));
+                }
+                res
+            },
+            Response::Handoff => {
+                "RES HANDOFF".to_string()
+            },
+            Response::Hello { hello } => {
+                format!("RES HELLO {}", hello)
             },
             Response::Info { info } => {
                 format!("RES INFO {}", info)
             }
             Response::Join => {
-                format!("RES JOIN")
+                "RES JOIN".to_string()
+            },
+            Response::Leave => {
+                "RES LEAVE".to_string()
             },
             Response::Lookup { location } => {
-                format!("RES LOOKUP {}", location.to_string())
+                format!("RES LOOKUP {}", location)
+            },
+            Response::LookupKey { identifier, location } => {
+                format!("RES LOOKUPKEY {} {}", identifier, location)
+            },
+            Response::LookupTrace { location, hops } => {
+                let mut res = format!("RES LOOKUPTRACE {}", location);
+                for hop in hops {
+                    res.push_str(&format!(" {} {}", hop.location, hop.rtt.as_micros()));
+                }
+                res
+            },
+            Response::Notify { accepted } => {
+                match accepted {
+                    true => "RES NOTIFY ACCEPTED".to_string(),
+                    false => "RES NOTIFY".to_string(),
+                }
+            },
+            Response::Ping => {
+                "RES PING".to_string()
+            },
+            Response::Put => {
+                "RES PUT".to_string()
             },
-            Response::Notify => {
-                format!("RES NOTIFY")
+            Response::ReplacePredecessor => {
+                "RES REPLACEPREDECESSOR".to_string()
+            },
+            Response::ReplaceSuccessor => {
+                "RES REPLACESUCCESSOR".to_string()
+            },
+            Response::Transfer { entries } => {
+                let mut res = "RES TRANSFER".to_string();
+                for (key, value) in entries {
+                    res.push_str(&format!(" {} {}", key, quote_value(value)));
+                }
+                res
             },
         };
-        if is_human_client {
-            res.push_str("\r\n");
+        /* Terminate the response the same way as the request, so that
+         * pipelined responses can be told apart. */
+        match is_human_client {
+            true => res.push_str("\r\n"),
+            false => res.push('\n'),
         }
         Ok(res)
     }
 }
 
+impl SicilyError {
+    /*
+     * Serialize the error as "ERR <code> <message>", to be sent back to a text client.
+     */
+    pub fn serialize(&self, is_human_client: bool) -> String {
+        let message = self.message().replace(['\r', '\n'], " ");
+        let mut res = format!("ERR {} {}", self.code(), message);
+        match is_human_client {
+            true => res.push_str("\r\n"),
+            false => res.push('\n'),
+        }
+        res
+    }
+}
+
 /*
  * Assert the given string array is with given length. Otherwise throw an error.
  */
-fn check_params_len(arr: &Vec<&str>, len: usize) -> Result<()> {
+fn check_params_len(arr: &[&str], len: usize) -> Result<()> {
     if arr.len() != len {
         return Err(
             format!("Invalid command. {} command takes {} parameters.", arr[0], len)
@@ -341,12 +1103,20 @@ fn check_params_len(arr: &Vec<&str>, len: usize) -> Result<()> {
  */
 fn parse_virtual_node_id(input: &str, node_list: Arc<NodeList>) -> Result<u8> {
     let virtual_node_id = str::parse::<u8>(input)?;
+    validate_virtual_node_id(virtual_node_id, node_list)?;
+    Ok(virtual_node_id)
+}
+
+/*
+ * Convenience function to make sure virtual_node_id is less than node_list length.
+ */
+fn validate_virtual_node_id(virtual_node_id: u8, node_list: Arc<NodeList>) -> Result<()> {
     if virtual_node_id as usize >= node_list.node_list.len() {
-        return Err(
-            "Invalid command. Virtual node number too large."
-            .into());
+        return Err(SicilyError::UnknownVirtualNode(
+            format!("Virtual node id {} does not exist. This node hosts {}.", virtual_node_id, node_list.node_list.len())
+        ));
     }
-    Ok(virtual_node_id)
+    Ok(())
 }
 
 /*
@@ -365,28 +1135,258 @@ fn parse_key(input: &str) -> Result<BigUint> {
     Ok(key)
 }
 
+/*
+ * Convenience function for storage requests, which take len parameters, optionally
+ * followed by FORWARDED. Return whether the request has been forwarded by another node.
+ */
+fn parse_forwarded(arr: &[&str], len: usize) -> Result<bool> {
+    if arr.len() == len {
+        return Ok(false);
+    }
+    check_params_len(arr, len + 1)?;
+    if arr[len].to_lowercase() != "forwarded" {
+        return Err(
+            format!("Invalid command. {} command only supports \"FORWARDED\" as last parameter.", arr[0])
+            .into());
+    }
+    Ok(true)
+}
+
+fn forwarded_suffix(forwarded: bool) -> &'static str {
+    match forwarded {
+        true => " FORWARDED",
+        false => "",
+    }
+}
+
+/*
+ * Convenience function to parse a list of key value pairs.
+ */
+/*
+ * Split a text request or response into words separated by spaces. A word starting
+ * with a double quote runs until the closing quote, so that values can hold spaces,
+ * with \" and \\ standing for a quote and a backslash inside.
+ */
+fn split_words(input: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = input.chars().peekable();
+    loop {
+        let mut word = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\')) => word.push(c),
+                        _ => return Err("Invalid command. Unsupported escape in quoted value.".into()),
+                    },
+                    Some(c) => word.push(c),
+                    None => return Err("Invalid command. Missing closing quote.".into()),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(' ')) {
+                return Err("Invalid command. Quoted value must be followed by a space.".into());
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ' ') {
+                word.push(c);
+            }
+        }
+        words.push(word);
+        if chars.next().is_none() {
+            return Ok(words);
+        }
+    }
+}
+
+/*
+ * Write a value as a single word of the text protocol, quoting it if needed.
+ */
+fn quote_value(value: &str) -> String {
+    if !value.is_empty() && !value.starts_with('"') && !value.contains(' ') {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn parse_entries(arr: &[&str]) -> Result<Vec<(BigUint, String)>> {
+    let mut entries = Vec::new();
+    for pair in arr.chunks(2) {
+        let key = parse_key(pair[0])?;
+        entries.push((key, pair[1].to_string()));
+    }
+    Ok(entries)
+}
+
+/*
+ * Convenience function for storage requests to find where the key lives.
+ * Return None if the node at virtual_node_id owns the key, otherwise look up
+ * the owner in the cluster and return its location.
+ * A request forwarded by another node has been routed already, and is always served
+ * locally: during churn two nodes may each believe the other one owns the key, and
+ * would otherwise bounce the request between them.
+ */
+async fn find_remote_owner(
+    virtual_node_id: u8,
+    key: &BigUint,
+    forwarded: bool,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Option<Location>> {
+    if forwarded {
+        return Ok(None);
+    }
+    let own_location = {
+        let node = node_list.node_list[virtual_node_id as usize].read().await;
+        if node.is_responsible_for(key) {
+            return Ok(None);
+        }
+        node.own_location()
+    };
+
+    let owner = process::find_successor(&own_location, key, node_list, config).await?;
+    if owner == own_location {
+        return Ok(None);
+    }
+    Ok(Some(owner))
+}
+
+/*
+ * Execute a request, and record it in the metrics.
+ */
 async fn execute_request(
     request: Request,
     node_list: Arc<NodeList>,
     config: Arc<Config>,
 ) -> Result<Response> {
+    let name = request.name();
+    let start = Instant::now();
+    let metrics = node_list.metrics.clone();
+    let result = dispatch_request(request, node_list, config).await;
+    metrics.record_request(name, start.elapsed(), result.is_ok());
+    result
+}
+
+/*
+ * Execute a request addressed to a virtual node of the local process, the same way
+ * as if it came in over the network, bounded by request_timeout.
+ * The future is boxed, since executing a request may issue further requests.
+ */
+pub fn execute_local_request(
+    request: Request,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+    Box::pin(async move {
+        validate_virtual_node_id(request.virtual_node_id(), node_list.clone())?;
+        let request_timeout = Duration::from_millis(config.request_timeout);
+        match tokio::time::timeout(request_timeout, execute_request(request, node_list, config.clone())).await {
+            Ok(result) => result,
+            Err(_) => {
+                Err(SicilyError::Timeout(
+                    format!("Local request timed out after {} ms.", config.request_timeout)
+                ))
+            }
+        }
+    })
+}
+
+async fn dispatch_request(
+    request: Request,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Response> {
+    /* A virtual node that left its cluster owns no range anymore. It only answers the
+     * requests that don't depend on it, and JOIN to take part in a cluster again. */
+    match request {
+        Request::Hello { .. } | Request::Info { .. } | Request::Join { .. } | Request::Ping { .. } => {},
+        _ => {
+            let node = node_list.node_list[request.virtual_node_id() as usize].read().await;
+            node.check_not_left()?;
+        }
+    }
+
     let response = match request {
         Request::ClosestPrecedingFinger { virtual_node_id, key } => {
             let location = {
-                let node = node_list.node_list[virtual_node_id as usize].lock().await;
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
                 node.closest_preceding_finger(key)?
             };
             Response::ClosestPrecedingFinger {
                 location,
             }
         },
+        Request::ConfirmTransfer { virtual_node_id, entries } => {
+            {
+                /* A key overwritten since it was transferred keeps its new value, which
+                 * is pulled again by the next transfer. */
+                let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                for (key, value) in entries.iter() {
+                    node.delete_value_if_unchanged(key, value);
+                }
+            }
+            Response::ConfirmTransfer
+        },
+        Request::Delete { virtual_node_id, key, forwarded } => {
+            match find_remote_owner(virtual_node_id, &key, forwarded, node_list.clone(), config.clone()).await? {
+                None => {
+                    let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                    node.check_writable()?;
+                    node.delete_value(&key);
+                },
+                Some(owner) => {
+                    process::delete(&owner, &key, node_list, config).await?;
+                }
+            }
+            Response::Delete
+        },
+        Request::FindSuccessor { virtual_node_id, key, path } => {
+            let (own_location, successor, next) = {
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
+                (node.own_location(), node.get_successor()?, node.closest_preceding_finger(key.clone())?)
+            };
+
+            /* Answer if the key falls between the node and its successor. Otherwise forward
+             * the lookup to the closest preceding finger, and relay its answer back. path holds
+             * the nodes the lookup visited, this one last. A lookup that doesn't come from
+             * another node starts here. Like an iterative lookup, it fails as soon as the next
+             * hop revisits a node or goes beyond the hop limit. */
+            let location = if arithmetic::is_in_range(
+                &key,
+                (&own_location.identifier, false),
+                (&successor.identifier, true)) || next == own_location {
+                successor
+            } else {
+                let mut path = path;
+                if path.is_empty() {
+                    path.push(own_location);
+                }
+                process::check_next_hop(&mut path, &next, &config)?;
+                process::forward_find_successor(&next, &key, path, node_list, config).await?
+            };
+            Response::FindSuccessor {
+                location,
+            }
+        },
+        Request::Get { virtual_node_id, key, forwarded } => {
+            let value = match find_remote_owner(virtual_node_id, &key, forwarded, node_list.clone(), config.clone()).await? {
+                None => {
+                    let node = node_list.node_list[virtual_node_id as usize].read().await;
+                    node.get_value(&key)
+                },
+                Some(owner) => {
+                    process::get(&owner, &key, node_list, config).await?
+                }
+            };
+            Response::Get {
+                value,
+            }
+        },
         Request::GetPredecessor { virtual_node_id } => {
             let location = {
-                let node = node_list.node_list[virtual_node_id as usize].lock().await;
-                match node.get_predecessor() {
-                    Ok(location) => Some(location),
-                    Err(_) => None,
-                }
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
+                node.get_predecessor().ok()
             };
             Response::GetPredecessor {
                 location,
@@ -394,17 +1394,47 @@ async fn execute_request(
         },
         Request::GetSuccessor { virtual_node_id } => {
             let location = {
-                let node = node_list.node_list[virtual_node_id as usize].lock().await;
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
                 node.get_successor()?
             };
             Response::GetSuccessor {
                 location,
             }
         },
-        Request::Info { virtual_node_id } => {
+        Request::GetSuccessorList { virtual_node_id } => {
+            let locations = {
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
+                node.get_successor_list()
+            };
+            Response::GetSuccessorList {
+                locations,
+            }
+        },
+        Request::Handoff { virtual_node_id, entries } => {
+            {
+                /* A leaving node still takes the keys of a predecessor leaving at the same
+                 * time, and hands them over once it has left. */
+                let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                node.check_not_left()?;
+                for (key, value) in entries {
+                    node.put_value(key, value);
+                }
+            }
+            Response::Handoff
+        },
+        Request::Hello { hello, .. } => {
+            hello.check(&config)?;
+            Response::Hello {
+                hello: Hello::new(&config),
+            }
+        },
+        Request::Info { virtual_node_id, json } => {
             let info = {
-                let node = node_list.node_list[virtual_node_id as usize].lock().await;
-                node.get_info()
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
+                match json {
+                    true => node.get_info_json()?,
+                    false => node.get_info(),
+                }
             };
             Response::Info {
                 info,
@@ -414,24 +1444,95 @@ async fn execute_request(
             membership::join(virtual_node_id, location, node_list, config).await?;
             Response::Join
         },
-        Request::Lookup { virtual_node_id, key } => {
+        Request::Leave { virtual_node_id } => {
+            membership::leave(virtual_node_id, node_list, config).await?;
+            Response::Leave
+        },
+        Request::Lookup { virtual_node_id, key, mode } => {
             let own_location = {
-                let node = node_list.node_list[virtual_node_id as usize].lock().await;
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
                 node.own_location()
             };
 
-            let location = process::find_successor(&own_location, &key, config).await?;
+            let mode = mode.unwrap_or(config.lookup_mode);
+            let location = process::find_successor_with_mode(&own_location, &key, mode, node_list, config).await?;
             Response::Lookup {
                 location
             }
         },
+        Request::LookupKey { virtual_node_id, key } => {
+            let own_location = {
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
+                node.own_location()
+            };
+
+            let identifier = arithmetic::compute_identifier(config.hash.as_ref(), config.id_bits as u32, &key);
+            let location = process::find_successor(&own_location, &identifier, node_list, config).await?;
+            Response::LookupKey {
+                identifier,
+                location,
+            }
+        },
+        Request::LookupTrace { virtual_node_id, key } => {
+            let own_location = {
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
+                node.own_location()
+            };
+
+            let (location, hops) = process::find_successor_traced(&own_location, &key, node_list, config).await?;
+            Response::LookupTrace {
+                location,
+                hops,
+            }
+        },
         Request::Notify { virtual_node_id, notifier } => {
+            let accepted = {
+                let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                node.notify_with(&notifier)
+            };
+            Response::Notify {
+                accepted,
+            }
+        },
+        Request::Ping { .. } => {
+            Response::Ping
+        },
+        Request::Put { virtual_node_id, key, value, forwarded } => {
+            match find_remote_owner(virtual_node_id, &key, forwarded, node_list.clone(), config.clone()).await? {
+                None => {
+                    let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                    node.check_writable()?;
+                    node.put_value(key, value);
+                },
+                Some(owner) => {
+                    process::put(&owner, &key, value, node_list, config).await?;
+                }
+            }
+            Response::Put
+        },
+        Request::ReplacePredecessor { virtual_node_id, departing, predecessor } => {
             {
-                let mut node = node_list.node_list[virtual_node_id as usize].lock().await;
-                node.notify_with(&notifier);
+                let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                node.replace_predecessor(&departing, predecessor);
             }
-            Response::Notify
-        }
+            Response::ReplacePredecessor
+        },
+        Request::ReplaceSuccessor { virtual_node_id, departing, successor } => {
+            {
+                let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                node.replace_successor(&departing, successor);
+            }
+            Response::ReplaceSuccessor
+        },
+        Request::Transfer { virtual_node_id, from, to } => {
+            let entries = {
+                let node = node_list.node_list[virtual_node_id as usize].read().await;
+                node.get_values_in_range(&from, &to)
+            };
+            Response::Transfer {
+                entries,
+            }
+        },
     };
 
     Ok(response)
@@ -439,18 +1540,40 @@ async fn execute_request(
 
 /*
  * Given network I/O buffer, parse the request, and execute it.
+ * Return the response to be sent back to client, which is an "ERR <code> <message>"
+ * line if the request failed.
  */
 pub async fn process_request(
     buf: &BytesMut,
     node_list: Arc<NodeList>,
     config: Arc<Config>,
+) -> String {
+    match handle_request(buf, node_list, config).await {
+        Ok(string) => string,
+        Err(err) => {
+            log::warn!("Could not process request: {}", err);
+            err.serialize(buf.ends_with(b"\r\n"))
+        }
+    }
+}
+
+async fn handle_request(
+    buf: &BytesMut,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
 ) -> Result<String> {
     /* Parse request. */
     let (request, is_human_client) = Request::parse_from_buf(
         buf,
         node_list.clone(),
         config.clone()
-    )?;
+    ).map_err(|e| e.refine(SicilyError::Parse))?;
+    if request.is_internal() {
+        return Err(SicilyError::Incompatible(format!(
+            "{} is only accepted from other nodes, over the binary protocol.",
+            request.name().to_uppercase()
+        )));
+    }
 
     /* Execute request. */
     let response = execute_request(request, node_list.clone(), config.clone()).await?;
@@ -458,4 +1581,223 @@ pub async fn process_request(
     /* Serialize the response to be sent back to client. */
     let string = response.serialize(is_human_client)?;
     Ok(string)
+}
+
+/*
+ * Given a binary frame, decode the request, and execute it.
+ * The encoded response frame is appended to output, or an error frame if the
+ * request failed. Return whether the request succeeded.
+ */
+pub async fn process_binary_request(
+    frame: &[u8],
+    output: &mut BytesMut,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<bool> {
+    let mut encoded = BytesMut::new();
+    let result = match handle_binary_request(frame, node_list, config).await {
+        Ok(response) => {
+            response.encode(&mut encoded)
+        },
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => {
+            output.extend_from_slice(&encoded);
+            Ok(true)
+        },
+        Err(err) => {
+            log::warn!("Could not process request: {}", err);
+            err.encode(output)?;
+            Ok(false)
+        }
+    }
+}
+
+async fn handle_binary_request(
+    frame: &[u8],
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Response> {
+    /* Decode request. */
+    let request = Request::decode(frame)
+        .map_err(|e| e.refine(SicilyError::Parse))?;
+    validate_virtual_node_id(request.virtual_node_id(), node_list.clone())
+        .map_err(|e| e.refine(SicilyError::Parse))?;
+
+    /* Execute request. */
+    let response = execute_request(request, node_list.clone(), config.clone()).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_forwarded_storage_requests_are_not_routed_again() {
+        /* Nodes a < c < b disagree about the owner of a key in (c, b]: a believes its
+         * successor b owns it, while b routes it through c, which believes a owns it. */
+        let (node_list, config) = testing::node_list(3, &[]);
+        let locations = testing::sorted_locations(&config);
+        let (a, c, b) = (&locations[0], &locations[1], &locations[2]);
+        let key = &c.identifier + 1u32;
+        {
+            let mut node = node_list.node_list[a.virtual_node_id as usize].write().await;
+            node.set_predecessor(None);
+            node.set_successor(Some(b.clone()));
+        }
+        {
+            let mut node = node_list.node_list[b.virtual_node_id as usize].write().await;
+            node.set_predecessor(None);
+            for i in 0..node.get_finger_len() {
+                node.set_finger(i, Some(c.clone())).unwrap();
+            }
+        }
+        {
+            let mut node = node_list.node_list[c.virtual_node_id as usize].write().await;
+            node.set_predecessor(None);
+            node.set_successor(Some(a.clone()));
+        }
+
+        /* a forwards the request to b, which stores it instead of bouncing it back. */
+        let request = Request::Put {
+            virtual_node_id: a.virtual_node_id,
+            key: key.clone(),
+            value: "value".to_string(),
+            forwarded: false,
+        };
+        let response = execute_local_request(request, node_list.clone(), config.clone()).await.unwrap();
+        assert!(matches!(response, Response::Put));
+        {
+            let node = node_list.node_list[b.virtual_node_id as usize].read().await;
+            assert_eq!(node.get_value(&key), Some("value".to_string()));
+        }
+
+        let request = Request::Get {
+            virtual_node_id: a.virtual_node_id,
+            key: key.clone(),
+            forwarded: false,
+        };
+        match execute_local_request(request, node_list.clone(), config.clone()).await.unwrap() {
+            Response::Get { value } => assert_eq!(value, Some("value".to_string())),
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    /*
+     * Chain the nodes in identifier order, with every finger pointing to the successor,
+     * so that a lookup visits them one by one.
+     */
+    async fn chain(node_list: &Arc<NodeList>, locations: &[Location]) {
+        for (i, location) in locations.iter().enumerate() {
+            let successor = locations[(i + 1) % locations.len()].clone();
+            let mut node = node_list.node_list[location.virtual_node_id as usize].write().await;
+            for j in 0..node.get_finger_len() {
+                node.set_finger(j, Some(successor.clone())).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recursive_lookup_is_bounded_by_max_lookup_hops() {
+        /* A lookup from a for a key past d visits a, b, c and d, which answers a. */
+        let (node_list, config) = testing::node_list(4, &["--lookup-mode", "recursive", "--max-lookup-hops", "4"]);
+        let locations = testing::sorted_locations(&config);
+        chain(&node_list, &locations).await;
+        let (a, d) = (&locations[0], &locations[3]);
+        let key = &d.identifier + 1u32;
+        let request = Request::FindSuccessor {
+            virtual_node_id: a.virtual_node_id,
+            key: key.clone(),
+            path: Vec::new(),
+        };
+        match execute_local_request(request, node_list.clone(), config.clone()).await.unwrap() {
+            Response::FindSuccessor { location } => assert_eq!(&location, a),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        /* With one hop less allowed, c gives up instead of forwarding the lookup to d. */
+        let (node_list, config) = testing::node_list(4, &["--lookup-mode", "recursive", "--max-lookup-hops", "3"]);
+        let locations = testing::sorted_locations(&config);
+        chain(&node_list, &locations).await;
+        let request = Request::FindSuccessor {
+            virtual_node_id: locations[0].virtual_node_id,
+            key: key.clone(),
+            path: Vec::new(),
+        };
+        let err = execute_local_request(request, node_list.clone(), config.clone()).await.unwrap_err();
+        match &err {
+            SicilyError::Routing(message) => assert!(message.contains(&locations[2].to_string())),
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recursive_lookup_fails_on_first_revisit() {
+        let (node_list, config) = testing::node_list(4, &["--lookup-mode", "recursive"]);
+        let locations = testing::sorted_locations(&config);
+        chain(&node_list, &locations).await;
+        let (a, b, d) = (&locations[0], &locations[1], &locations[3]);
+
+        /* A lookup that went through b and then a, which forwards it to b again, fails at a
+         * with the cycle instead of going round until the hop limit. */
+        let request = Request::FindSuccessor {
+            virtual_node_id: a.virtual_node_id,
+            key: &d.identifier + 1u32,
+            path: vec![b.clone(), a.clone()],
+        };
+        let err = execute_local_request(request, node_list.clone(), config.clone()).await.unwrap_err();
+        match &err {
+            SicilyError::Routing(message) => {
+                assert!(message.contains(&format!("revisited {}", b)), "{}", message);
+                assert!(message.contains(&format!("{} -> {} -> {}", b, a, b)), "{}", message);
+            },
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_text_values_can_hold_spaces_and_quotes() {
+        let (node_list, config) = testing::node_list(1, &[]);
+        let cases = [
+            ("PUT 0 42 \"hello world\"\r\n", "RES PUT\r\n"),
+            ("GET 0 42\r\n", "RES GET VALUE \"hello world\"\r\n"),
+            ("PUT 0 42 \"say \\\"hi\\\" \\\\o/\"\r\n", "RES PUT\r\n"),
+            ("GET 0 42\r\n", "RES GET VALUE \"say \\\"hi\\\" \\\\o/\"\r\n"),
+            ("PUT 0 42 plain\"word\r\n", "RES PUT\r\n"),
+            ("GET 0 42\r\n", "RES GET VALUE plain\"word\r\n"),
+            ("PUT 0 42 \"unterminated\r\n", "ERR PARSE "),
+        ];
+        for (request, expected) in cases {
+            let response = process_request(&BytesMut::from(request), node_list.clone(), config.clone()).await;
+            assert!(response.starts_with(expected), "{:?} was answered with {:?}", request, response);
+        }
+
+        for value in ["", "a b", "\"", "\\", "x\\\"y"] {
+            assert_eq!(split_words(&quote_value(value)).unwrap(), vec![value.to_string()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_text_requests_are_answered_with_err_lines() {
+        let (node_list, config) = testing::node_list(2, &[]);
+        let cases = [
+            ("GET 0 not-a-number\r\n", "ERR PARSE "),
+            ("FROBNICATE 0\r\n", "ERR PARSE "),
+            ("GET 9 42\r\n", "ERR UNKNOWN_VNODE "),
+        ];
+        for (request, expected) in cases {
+            let response = process_request(&BytesMut::from(request), node_list.clone(), config.clone()).await;
+            assert!(response.starts_with(expected), "{:?} was answered with {:?}", request, response);
+            assert!(response.ends_with("\r\n"));
+            assert_eq!(response.lines().count(), 1);
+        }
+
+        /* A machine client, which ends its lines with a bare newline, gets one back. */
+        let response = process_request(&BytesMut::from("GET 9 42\n"), node_list.clone(), config.clone()).await;
+        assert!(response.starts_with("ERR UNKNOWN_VNODE "));
+        assert!(response.ends_with('\n') && !response.ends_with("\r\n"));
+    }
 }
\ No newline at end of file
@@ -16,16 +16,30 @@
  */
 
 use pnet::datalink;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
 use structopt::StructOpt;
+use structopt::clap::ErrorKind;
 
 use crate::constants::*;
+use crate::hash::{ self, HashAlgorithm };
+use crate::location::{ join_host_port, split_host_port };
 use crate::utils::Result;
 
 /*
  * The Params struct is to gather params input given by commandline
  * when starting the sicily program.
+ * The same struct is read from the config file, with the same names as the
+ * commandline flags, and from the SICILY_* environment variables named after them.
  */
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 #[structopt(
     name = "sicily",
     version = env!("CARGO_PKG_VERSION"),
@@ -33,6 +47,14 @@ use crate::utils::Result;
     about = "sicily server"
 )]
 struct Params {
+    #[structopt(
+        name = "config",
+        long = "--config",
+        about = "Path of a TOML config file. Commandline flags and environment variables take precedence over it."
+    )]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
     #[structopt(
         name = "port",
         long = "--port",
@@ -47,6 +69,20 @@ struct Params {
     )]
     pub host: Option<String>,
 
+    #[structopt(
+        name = "bind address",
+        long = "--bind",
+        about = "Address to listen on, as host or host:port. The port defaults to the port of --port. Defaults to the advertised host if it is a loopback address, so that a local node is not exposed, and to every interface otherwise."
+    )]
+    pub bind: Option<String>,
+
+    #[structopt(
+        name = "advertise address",
+        long = "--advertise",
+        about = "Address other nodes reach this node at, as host or host:port. Takes precedence over --host. The port defaults to the port of --port."
+    )]
+    pub advertise: Option<String>,
+
     #[structopt(
         name = "output buffer size",
         long = "--output-buffer-size",
@@ -61,12 +97,82 @@ struct Params {
     )]
     pub stabilize_frequency: Option<u64>,
 
+    #[structopt(
+        name = "pool max idle per peer",
+        long = "--pool-max-idle-per-peer",
+        about = "Maximum number of idle connections kept open to each peer."
+    )]
+    pub pool_max_idle_per_peer: Option<usize>,
+
+    #[structopt(
+        name = "pool max connections per peer",
+        long = "--pool-max-connections-per-peer",
+        about = "Maximum number of connections open to each peer, idle or in use. Requests wait for a connection beyond that. Must be larger than 0."
+    )]
+    pub pool_max_connections_per_peer: Option<usize>,
+
+    #[structopt(
+        name = "pool idle timeout",
+        long = "--pool-idle-timeout",
+        about = "Time in milliseconds after which an idle connection to a peer is closed."
+    )]
+    pub pool_idle_timeout: Option<u64>,
+
+    #[structopt(
+        name = "connect timeout",
+        long = "--connect-timeout",
+        about = "Timeout in milliseconds for connecting to a peer. Must be larger than 0."
+    )]
+    pub connect_timeout: Option<u64>,
+
+    #[structopt(
+        name = "read timeout",
+        long = "--read-timeout",
+        about = "Timeout in milliseconds for reading a response from a peer. Must be larger than 0."
+    )]
+    pub read_timeout: Option<u64>,
+
+    #[structopt(
+        name = "request timeout",
+        long = "--request-timeout",
+        about = "Timeout in milliseconds for a whole request to a peer, retries included. Must be larger than 0."
+    )]
+    pub request_timeout: Option<u64>,
+
+    #[structopt(
+        name = "request retries",
+        long = "--request-retries",
+        about = "Number of times a failed request to a peer is retried. Requests that change state are only retried if they failed before being sent."
+    )]
+    pub request_retries: Option<u32>,
+
+    #[structopt(
+        name = "retry backoff",
+        long = "--retry-backoff",
+        about = "Base delay in milliseconds between retries, doubled on every retry."
+    )]
+    pub retry_backoff: Option<u64>,
+
     #[structopt(
         name = "Identifier bits",
         long = "--id-bits",
         about = "Identifier bits. Must be an integer between 8 to 255."
     )]
     pub id_bits: Option<u8>,
+
+    #[structopt(
+        name = "hash algorithm",
+        long = "--hash",
+        about = "Hash algorithm used to compute identifiers, one of \"sha256\", \"sha1\" or \"fnv1a\". Every node of a ring must use the same one."
+    )]
+    pub hash: Option<String>,
+
+    #[structopt(
+        name = "cluster name",
+        long = "--cluster-name",
+        about = "Name of the cluster. Nodes refuse to talk to nodes of another cluster."
+    )]
+    pub cluster_name: Option<String>,
     
     #[structopt(
         name = "virtual node number",
@@ -74,6 +180,154 @@ struct Params {
         about = "Virtual node number. Must be an integer between 1 to 32."
     )]
     pub virtual_node_number: Option<u8>,
+
+    #[structopt(
+        name = "successor list length",
+        long = "--successor-list-len",
+        about = "Number of successors each virtual node keeps for failover. Must be larger than 0."
+    )]
+    pub successor_list_len: Option<u8>,
+
+    #[structopt(
+        name = "lookup mode",
+        long = "--lookup-mode",
+        about = "How lookups are routed, either \"iterative\" or \"recursive\"."
+    )]
+    pub lookup_mode: Option<String>,
+
+    #[structopt(
+        name = "max lookup hops",
+        long = "--max-lookup-hops",
+        about = "Maximum number of nodes a lookup visits before giving up. Must be larger than 0."
+    )]
+    pub max_lookup_hops: Option<u32>,
+
+    #[structopt(
+        name = "metrics port",
+        long = "--metrics-port",
+        about = "Port of the HTTP endpoint serving Prometheus metrics at /metrics, on the host of --bind. Disabled if not set."
+    )]
+    pub metrics_port: Option<u16>,
+
+    #[structopt(
+        name = "join seed",
+        long = "--join",
+        number_of_values = 1,
+        use_delimiter = true,
+        about = "Seed node to join at startup, as host:port[:virtual_node_id]. Can be repeated, or given comma separated. Every virtual node joins the first reachable seed."
+    )]
+    pub join: Vec<String>,
+
+    #[structopt(
+        name = "join retry interval",
+        long = "--join-retry-interval",
+        about = "Time in milliseconds to wait before trying the join seeds again, when none of them could be joined. Must be larger than 0."
+    )]
+    pub join_retry_interval: Option<u64>,
+}
+
+impl Params {
+    /*
+     * Read params from a TOML config file.
+     */
+    fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file {}: {}", path.display(), e))?;
+        let params: Params = toml::from_str(&content)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        Ok(params)
+    }
+
+    /*
+     * Read params from environment variables. Every param is read from the variable
+     * named after its flag, for example SICILY_POOL_IDLE_TIMEOUT for --pool-idle-timeout,
+     * and parsed the same way. Variables not matching any flag are ignored.
+     */
+    fn from_env(env: &HashMap<String, String>) -> Result<Self> {
+        let mut params = Params::default();
+        for (key, value) in env.iter() {
+            let name = match key.strip_prefix("SICILY_") {
+                Some(name) => name,
+                None => continue,
+            };
+            let arg = format!("--{}={}", name.to_lowercase().replace('_', "-"), value);
+            match Params::from_iter_safe(&["sicily", &arg]) {
+                Ok(env_params) => params = params.merge(env_params),
+                Err(e) if e.kind == ErrorKind::UnknownArgument => continue,
+                Err(e) => {
+                    return Err(format!("Invalid environment variable {}: {}", key, e.message).into());
+                },
+            }
+        }
+        Ok(params)
+    }
+
+    /*
+     * Fill the params missing from self with the ones from other.
+     */
+    fn merge(self, other: Params) -> Self {
+        Self {
+            config: self.config.or(other.config),
+            port: self.port.or(other.port),
+            host: self.host.or(other.host),
+            bind: self.bind.or(other.bind),
+            advertise: self.advertise.or(other.advertise),
+            output_buffer_size: self.output_buffer_size.or(other.output_buffer_size),
+            stabilize_frequency: self.stabilize_frequency.or(other.stabilize_frequency),
+            pool_max_idle_per_peer: self.pool_max_idle_per_peer.or(other.pool_max_idle_per_peer),
+            pool_max_connections_per_peer: self.pool_max_connections_per_peer.or(other.pool_max_connections_per_peer),
+            pool_idle_timeout: self.pool_idle_timeout.or(other.pool_idle_timeout),
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            read_timeout: self.read_timeout.or(other.read_timeout),
+            request_timeout: self.request_timeout.or(other.request_timeout),
+            request_retries: self.request_retries.or(other.request_retries),
+            retry_backoff: self.retry_backoff.or(other.retry_backoff),
+            id_bits: self.id_bits.or(other.id_bits),
+            hash: self.hash.or(other.hash),
+            cluster_name: self.cluster_name.or(other.cluster_name),
+            virtual_node_number: self.virtual_node_number.or(other.virtual_node_number),
+            successor_list_len: self.successor_list_len.or(other.successor_list_len),
+            lookup_mode: self.lookup_mode.or(other.lookup_mode),
+            max_lookup_hops: self.max_lookup_hops.or(other.max_lookup_hops),
+            metrics_port: self.metrics_port.or(other.metrics_port),
+            join: if self.join.is_empty() { other.join } else { self.join },
+            join_retry_interval: self.join_retry_interval.or(other.join_retry_interval),
+        }
+    }
+}
+
+/*
+ * How a lookup is routed through the cluster.
+ * - Iterative: the originating node asks every hop for the next one itself.
+ * - Recursive: every hop forwards the lookup to the next one, and the answer
+ *   returns along the same path.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupMode {
+    Iterative,
+    Recursive,
+}
+
+impl LookupMode {
+    pub fn parse(input: &str) -> Result<Self> {
+        match input.to_lowercase().as_str() {
+            "iterative" => Ok(LookupMode::Iterative),
+            "recursive" => Ok(LookupMode::Recursive),
+            _ => Err(
+                format!("Invalid lookup mode {}. Should be either \"iterative\" or \"recursive\".", input)
+                .into()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for LookupMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupMode::Iterative => write!(f, "ITERATIVE"),
+            LookupMode::Recursive => write!(f, "RECURSIVE"),
+        }
+    }
 }
 
 /*
@@ -83,10 +337,28 @@ struct Params {
 pub struct Config {
     pub port: u16,
     pub host: String,
+    pub bind: String,
+    pub advertise_port: u16,
     pub output_buffer_size: usize,
     pub stabilize_frequency: u64,
+    pub pool_max_idle_per_peer: usize,
+    pub pool_max_connections_per_peer: usize,
+    pub pool_idle_timeout: u64,
+    pub connect_timeout: u64,
+    pub read_timeout: u64,
+    pub request_timeout: u64,
+    pub request_retries: u32,
+    pub retry_backoff: u64,
     pub id_bits: u8,
+    pub hash: Arc<dyn HashAlgorithm>,
+    pub cluster_name: String,
     pub virtual_node_number: u8,
+    pub successor_list_len: u8,
+    pub lookup_mode: LookupMode,
+    pub max_lookup_hops: u32,
+    pub metrics_port: Option<u16>,
+    pub join: Vec<String>,
+    pub join_retry_interval: u64,
 }
 
 fn parse_local_ip() -> Result<String> {
@@ -103,8 +375,101 @@ fn parse_local_ip() -> Result<String> {
     Err("Cannot parse local IP".into())
 }
 
+/*
+ * Whether a host is a loopback address, or the localhost name.
+ */
+fn is_loopback_host(host: &str) -> bool {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => host.eq_ignore_ascii_case("localhost"),
+    }
+}
+
+/*
+ * The host to listen on when no bind address is given. A node advertising a loopback
+ * address can only be reached locally, so it keeps listening there. Any other node
+ * listens on every interface, so that the peers dialing its advertised host reach it,
+ * even behind NAT where that host is not an address of the machine.
+ */
+fn default_bind_host(advertise_host: &str) -> &str {
+    if is_loopback_host(advertise_host) {
+        return advertise_host;
+    }
+    match advertise_host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => BIND_HOST_V6,
+        _ => BIND_HOST,
+    }
+}
+
+/*
+ * Convenience function to parse a timeout or an interval in milliseconds, which cannot be 0.
+ */
+fn parse_timeout(input: Option<u64>, default: u64, name: &str) -> Result<u64> {
+    match input {
+        Some(0) => Err(format!("{} cannot be 0.", name).into()),
+        Some(timeout) => Ok(timeout),
+        None => Ok(default),
+    }
+}
+
+/*
+ * Parse the config of the program.
+ * Every param is taken from, in order of precedence:
+ *   - the commandline flags,
+ *   - the SICILY_* environment variables,
+ *   - the config file given by --config,
+ *   - the defaults in constants.rs.
+ */
 pub fn parse_params() -> Result<Config> {
-    let params = Params::from_args();
+    load_params(Params::from_args(), &std::env::vars().collect())
+}
+
+/*
+ * Parse the config from the given commandline arguments, the first one being the
+ * program name, with the same precedence as parse_params().
+ * Unlike parse_params(), invalid arguments are returned as an error instead of
+ * exiting the process, which suits programs embedding sicily.
+ */
+pub fn parse_params_from<I>(args: I) -> Result<Config>
+where
+    I: IntoIterator,
+    I::Item: Into<OsString> + Clone,
+{
+    parse_params_from_env(args, &std::env::vars().collect())
+}
+
+/*
+ * Same as parse_params_from(), with the environment variables taken from env instead
+ * of the environment of the process.
+ */
+pub fn parse_params_from_env<I>(args: I, env: &HashMap<String, String>) -> Result<Config>
+where
+    I: IntoIterator,
+    I::Item: Into<OsString> + Clone,
+{
+    let params = Params::from_iter_safe(args).map_err(|e| e.message)?;
+    load_params(params, env)
+}
+
+/*
+ * Merge the environment variables and the config file into params, and build the config.
+ */
+fn load_params(params: Params, env: &HashMap<String, String>) -> Result<Config> {
+    let params = params.merge(Params::from_env(env)?);
+    let params = match &params.config {
+        Some(path) => {
+            let file_params = Params::from_file(path)?;
+            params.merge(file_params)
+        },
+        None => params,
+    };
+    build_config(params)
+}
+
+/*
+ * Validate params and fill the missing ones with defaults.
+ */
+fn build_config(params: Params) -> Result<Config> {
 
     /* Parse port. */
     let port = match params.port {
@@ -129,11 +494,67 @@ pub fn parse_params() -> Result<Config> {
         None => STABILIZE_FREQUENCY,
     };
 
-    /* Parse host identifier from input.
+    /* Parse connection pool settings. */
+    let pool_max_idle_per_peer = match params.pool_max_idle_per_peer {
+        Some(pool_max_idle_per_peer) => pool_max_idle_per_peer,
+        None => POOL_MAX_IDLE_PER_PEER,
+    };
+    let pool_max_connections_per_peer = match params.pool_max_connections_per_peer {
+        Some(pool_max_connections_per_peer) => {
+            if pool_max_connections_per_peer == 0 {
+                return Err("Pool max connections per peer cannot be 0.".into());
+            }
+            pool_max_connections_per_peer
+        },
+        None => POOL_MAX_CONNECTIONS_PER_PEER,
+    };
+    let pool_idle_timeout = match params.pool_idle_timeout {
+        Some(pool_idle_timeout) => pool_idle_timeout,
+        None => POOL_IDLE_TIMEOUT,
+    };
+
+    /* Parse timeouts and retries of requests to peers. */
+    let connect_timeout = parse_timeout(params.connect_timeout, CONNECT_TIMEOUT, "Connect timeout")?;
+    let read_timeout = parse_timeout(params.read_timeout, READ_TIMEOUT, "Read timeout")?;
+    let request_timeout = parse_timeout(params.request_timeout, REQUEST_TIMEOUT, "Request timeout")?;
+    let request_retries = match params.request_retries {
+        Some(request_retries) => request_retries,
+        None => REQUEST_RETRIES,
+    };
+    let retry_backoff = match params.retry_backoff {
+        Some(retry_backoff) => retry_backoff,
+        None => RETRY_BACKOFF,
+    };
+
+    /* Parse advertised address. The host is the host identifier of the node.
      * If no input, then try to automatically find one. */
-    let host = match params.host {
-        Some(host) => host,
-        None => parse_local_ip()?
+    let (host, advertise_port) = match params.advertise {
+        Some(advertise) => {
+            let (advertise_host, advertise_port) = split_host_port(&advertise)?;
+            (advertise_host, advertise_port.unwrap_or(port))
+        },
+        None => {
+            let host = match params.host {
+                Some(host) => host,
+                None => parse_local_ip()?
+            };
+            (host, port)
+        },
+    };
+
+    /* Parse bind address. It defaults to a host the advertised one is reachable on. */
+    let bind = match params.bind {
+        Some(bind) => {
+            let (bind_host, bind_port) = split_host_port(&bind)?;
+            if is_loopback_host(&bind_host) && !is_loopback_host(&host) {
+                log::warn!(
+                    "Listening on loopback address {} while advertising {}. Other nodes will not reach this node.",
+                    bind_host, host
+                );
+            }
+            join_host_port(&bind_host, bind_port.unwrap_or(port))
+        },
+        None => join_host_port(default_bind_host(&host), port),
     };
 
     /* Parse identifier bits. */
@@ -147,6 +568,24 @@ pub fn parse_params() -> Result<Config> {
         None => ID_BITS,
     };
 
+    /* Parse hash algorithm. */
+    let hash = match params.hash {
+        Some(hash) => hash::from_name(&hash)?,
+        None => hash::from_name(HASH_ALGORITHM)?,
+    };
+    if id_bits as u32 > hash.bits() {
+        return Err(
+            format!("Identifier bits cannot be larger than {}, the bits of hash algorithm {}.", hash.bits(), hash.name())
+            .into()
+        );
+    }
+
+    /* Parse cluster name. It is sent as a single word of the text protocol. */
+    let cluster_name = params.cluster_name.unwrap_or_else(|| CLUSTER_NAME.to_string());
+    if cluster_name.is_empty() || cluster_name.contains(char::is_whitespace) {
+        return Err("Cluster name cannot be empty or contain whitespaces.".into());
+    }
+
     /* Parse virtual node number. */
     let virtual_node_number = match params.virtual_node_number {
         Some(virtual_node_number) => {
@@ -160,13 +599,196 @@ pub fn parse_params() -> Result<Config> {
         },
         None => VIRTUAL_NODE_NUMBER,
     };
+
+    /* Parse successor list length. */
+    let successor_list_len = match params.successor_list_len {
+        Some(successor_list_len) => {
+            if successor_list_len == 0 {
+                return Err("Successor list length cannot be 0.".into());
+            }
+            successor_list_len
+        },
+        None => SUCCESSOR_LIST_LEN,
+    };
+
+    /* Parse lookup mode. */
+    let lookup_mode = match params.lookup_mode {
+        Some(lookup_mode) => LookupMode::parse(&lookup_mode)?,
+        None => LookupMode::parse(LOOKUP_MODE)?,
+    };
+
+    /* Parse max lookup hops. */
+    let max_lookup_hops = match params.max_lookup_hops {
+        Some(max_lookup_hops) => {
+            if max_lookup_hops == 0 {
+                return Err("Max lookup hops cannot be 0.".into());
+            }
+            max_lookup_hops
+        },
+        None => MAX_LOOKUP_HOPS,
+    };
+
+    /* Parse join retry interval. */
+    let join_retry_interval = parse_timeout(params.join_retry_interval, JOIN_RETRY_INTERVAL, "Join retry interval")?;
+
     let config = Config {
         port,
         host,
+        bind,
+        advertise_port,
         output_buffer_size,
         stabilize_frequency,
+        pool_max_idle_per_peer,
+        pool_max_connections_per_peer,
+        pool_idle_timeout,
+        connect_timeout,
+        read_timeout,
+        request_timeout,
+        request_retries,
+        retry_backoff,
         id_bits,
+        hash,
+        cluster_name,
         virtual_node_number,
+        successor_list_len,
+        lookup_mode,
+        max_lookup_hops,
+        metrics_port: params.metrics_port,
+        join: params.join,
+        join_retry_interval,
     };
     Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * Parse commandline arguments only. Unlike parse_params(), this never reads the
+     * environment of the test run.
+     */
+    fn cli_params(args: &[&str]) -> Params {
+        Params::from_iter(args)
+    }
+
+    #[test]
+    fn test_file_params_fill_missing_cli_params() {
+        let file_params: Params = toml::from_str(r#"
+            port = 9000
+            host = "10.0.0.1"
+            lookup-mode = "recursive"
+        "#).unwrap();
+        let params = cli_params(&["sicily", "--port", "9001"]);
+        let config = build_config(params.merge(file_params)).unwrap();
+        assert_eq!(config.port, 9001);
+        assert_eq!(config.host, "10.0.0.1");
+        assert_eq!(config.bind, "0.0.0.0:9001");
+        assert_eq!(config.lookup_mode, LookupMode::Recursive);
+        assert_eq!(config.id_bits, ID_BITS);
+    }
+
+    #[test]
+    fn test_file_params_are_validated() {
+        let file_params: Params = toml::from_str("host = \"10.0.0.1\"\nvirtual-node-number = 0").unwrap();
+        assert!(build_config(file_params).is_err());
+        assert!(toml::from_str::<Params>("unknown-param = 1").is_err());
+
+        let file_params: Params = toml::from_str("host = \"10.0.0.1\"\nhash = \"fnv1a\"\nid-bits = 128").unwrap();
+        assert!(build_config(file_params).is_err());
+        let file_params: Params = toml::from_str("host = \"10.0.0.1\"\nhash = \"md5\"").unwrap();
+        assert!(build_config(file_params).is_err());
+    }
+
+    #[test]
+    fn test_bind_and_advertise() {
+        let params = cli_params(&[
+            "sicily",
+            "--port", "8820",
+            "--bind", "192.168.0.2",
+            "--advertise", "203.0.113.7:18820",
+        ]);
+        let config = build_config(params).unwrap();
+        assert_eq!(config.bind, "192.168.0.2:8820");
+        assert_eq!(config.host, "203.0.113.7");
+        assert_eq!(config.advertise_port, 18820);
+
+        let params = cli_params(&["sicily", "--host", "10.0.0.1", "--bind", "0.0.0.0:9000"]);
+        let config = build_config(params).unwrap();
+        assert_eq!(config.bind, "0.0.0.0:9000");
+        assert_eq!(config.host, "10.0.0.1");
+        assert_eq!(config.advertise_port, PORT);
+
+        let params = cli_params(&["sicily", "--host", "10.0.0.1", "--bind", "0.0.0.0:http"]);
+        assert!(build_config(params).is_err());
+
+        /* Without --bind, a node advertising a reachable host listens where peers dial it. */
+        let params = cli_params(&["sicily", "--host", "10.0.0.1"]);
+        let config = build_config(params).unwrap();
+        assert_eq!(config.bind, "0.0.0.0:8820");
+        assert_eq!(config.host, "10.0.0.1");
+
+        let params = cli_params(&["sicily", "--port", "9000", "--advertise", "[2001:db8::1]:19000"]);
+        let config = build_config(params).unwrap();
+        assert_eq!(config.bind, "[::]:9000");
+
+        let params = cli_params(&["sicily", "--host", "127.0.0.1"]);
+        let config = build_config(params).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:8820");
+    }
+
+    #[test]
+    fn test_join_seeds() {
+        let params = cli_params(&[
+            "sicily",
+            "--host", "10.0.0.1",
+            "--join", "10.0.0.2:8820",
+            "--join", "10.0.0.3:8820:1,[::1]:8820",
+        ]);
+        let config = build_config(params).unwrap();
+        assert_eq!(config.join, vec!["10.0.0.2:8820", "10.0.0.3:8820:1", "[::1]:8820"]);
+
+        let file_params: Params = toml::from_str("join = [\"10.0.0.4:8820\"]").unwrap();
+        let params = cli_params(&["sicily", "--host", "10.0.0.1"]);
+        let config = build_config(params.merge(file_params)).unwrap();
+        assert_eq!(config.join, vec!["10.0.0.4:8820"]);
+    }
+
+    #[test]
+    fn test_cli_takes_precedence_over_env_over_file_over_defaults() {
+        let path = std::env::temp_dir().join(format!("sicily-config-test-{}.toml", std::process::id()));
+        fs::write(&path, "host = \"10.0.0.1\"\noutput-buffer-size = 3\npool-idle-timeout = 3000\nretry-backoff = 30").unwrap();
+        let params = cli_params(&["sicily", "--config", path.to_str().unwrap(), "--output-buffer-size", "1"]);
+        let env = HashMap::from([
+            ("SICILY_OUTPUT_BUFFER_SIZE".to_string(), "2".to_string()),
+            ("SICILY_POOL_IDLE_TIMEOUT".to_string(), "2000".to_string()),
+            ("SICILY_UNRELATED".to_string(), "ignored".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+        ]);
+        let config = load_params(params, &env);
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.output_buffer_size, 1);
+        assert_eq!(config.pool_idle_timeout, 2000);
+        assert_eq!(config.retry_backoff, 30);
+        assert_eq!(config.read_timeout, READ_TIMEOUT);
+    }
+
+    #[test]
+    fn test_env_params() {
+        let env = HashMap::from([
+            ("SICILY_HOST".to_string(), "10.0.0.1".to_string()),
+            ("SICILY_JOIN".to_string(), "10.0.0.2:8820,10.0.0.3:8820:1".to_string()),
+        ]);
+        let config = parse_params_from_env(&["sicily"], &env).unwrap();
+        assert_eq!(config.host, "10.0.0.1");
+        assert_eq!(config.join, vec!["10.0.0.2:8820", "10.0.0.3:8820:1"]);
+
+        let env = HashMap::from([
+            ("SICILY_HOST".to_string(), "10.0.0.1".to_string()),
+            ("SICILY_PORT".to_string(), "http".to_string()),
+        ]);
+        assert!(parse_params_from_env(&["sicily"], &env).is_err());
+    }
 }
\ No newline at end of file
@@ -17,9 +17,31 @@
 
 /* Networking part. */
 pub const PORT: u16 = 8820;
+pub const BIND_HOST: &str = "0.0.0.0";
+pub const BIND_HOST_V6: &str = "::";
 pub const OUTPUT_BUFFER_SIZE: usize = 1024;
 pub const STABILIZE_FREQUENCY: u64 = 1000;
+pub const JOIN_RETRY_INTERVAL: u64 = 1000;
+pub const POOL_MAX_IDLE_PER_PEER: usize = 8;
+pub const POOL_MAX_CONNECTIONS_PER_PEER: usize = 64;
+pub const POOL_IDLE_TIMEOUT: u64 = 30000;
+pub const CONNECT_TIMEOUT: u64 = 1000;
+pub const READ_TIMEOUT: u64 = 1000;
+pub const REQUEST_TIMEOUT: u64 = 3000;
+pub const REQUEST_RETRIES: u32 = 2;
+pub const RETRY_BACKOFF: u64 = 50;
+
+/* Wire protocol part. */
+pub const BINARY_PROTOCOL_HANDSHAKE: u8 = 0xb1;
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+pub const IDENTIFIER_BYTES: usize = 32;
 
 /* Algorithm part. */
 pub const ID_BITS: u8 = 32;
-pub const VIRTUAL_NODE_NUMBER: u8 = 8;
\ No newline at end of file
+pub const HASH_ALGORITHM: &str = "sha256";
+pub const CLUSTER_NAME: &str = "sicily";
+pub const VIRTUAL_NODE_NUMBER: u8 = 8;
+pub const SUCCESSOR_LIST_LEN: u8 = 4;
+pub const LOOKUP_MODE: &str = "iterative";
+pub const MAX_LOOKUP_HOPS: u32 = 64;
\ No newline at end of file
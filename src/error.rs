@@ -0,0 +1,169 @@
+/* 
+ * This file is part of the Sicily distribution (https://github.com/JeepYiheihou/sicily).
+ * Copyright (c) 2021 Jiachen Bai.
+ * 
+ * This program is free software: you can redistribute it and/or modify  
+ * it under the terms of the GNU General Public License as published by  
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU 
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License 
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+use std::string::FromUtf8Error;
+
+use crate::location::Location;
+
+/*
+ * Errors reported to clients, and by peers.
+ * A failed request is answered with the code and the message of the error, so that
+ * the client can turn the answer back into the same error.
+ * - Parse: the request could not be parsed or decoded.
+ * - UnknownVirtualNode: the request is addressed to a virtual node that doesn't exist.
+ * - Routing: a lookup didn't converge, either because it revisited a node or because
+ *   it took more hops than allowed. This usually means the fingers or successors of
+ *   some nodes are inconsistent.
+ * - Timeout: a request didn't complete in time. It is kept apart from other errors,
+ *   so that callers can tell a slow or hung peer from one that refused the request.
+ * - Peer: a peer couldn't be reached, or closed the connection. A virtual node that
+ *   is leaving or has left its cluster also rejects requests it can't serve anymore.
+ * - Incompatible: a peer talks another protocol version, belongs to another cluster,
+ *   or computes identifiers differently.
+ * - Internal: any other failure while executing a request.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SicilyError {
+    Parse(String),
+    UnknownVirtualNode(String),
+    Routing(String),
+    Timeout(String),
+    Peer(String),
+    Incompatible(String),
+    Internal(String),
+}
+
+impl SicilyError {
+    /*
+     * Error of a lookup that didn't converge. The path holds the nodes visited
+     * by the lookup, in order.
+     */
+    pub fn routing(message: String, path: &[Location]) -> Self {
+        let path: Vec<String> = path.iter().map(|location| location.to_string()).collect();
+        SicilyError::Routing(format!("{} Path: {}.", message, path.join(" -> ")))
+    }
+
+    /*
+     * Turn an Internal error, which is what plain messages become, into the given
+     * variant, for callers that know better what failed. Other errors are kept.
+     */
+    pub fn refine(self, variant: impl FnOnce(String) -> SicilyError) -> Self {
+        match self {
+            SicilyError::Internal(message) => variant(message),
+            err => err,
+        }
+    }
+
+    /*
+     * Rebuild an error from its code and message, as answered by a peer.
+     */
+    pub fn from_code(code: &str, message: String) -> Self {
+        match code {
+            "PARSE" => SicilyError::Parse(message),
+            "UNKNOWN_VNODE" => SicilyError::UnknownVirtualNode(message),
+            "ROUTING" => SicilyError::Routing(message),
+            "TIMEOUT" => SicilyError::Timeout(message),
+            "PEER" => SicilyError::Peer(message),
+            "INCOMPATIBLE" => SicilyError::Incompatible(message),
+            _ => SicilyError::Internal(message),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            SicilyError::Parse(_) => "PARSE",
+            SicilyError::UnknownVirtualNode(_) => "UNKNOWN_VNODE",
+            SicilyError::Routing(_) => "ROUTING",
+            SicilyError::Timeout(_) => "TIMEOUT",
+            SicilyError::Peer(_) => "PEER",
+            SicilyError::Incompatible(_) => "INCOMPATIBLE",
+            SicilyError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            SicilyError::Parse(message) => message,
+            SicilyError::UnknownVirtualNode(message) => message,
+            SicilyError::Routing(message) => message,
+            SicilyError::Timeout(message) => message,
+            SicilyError::Peer(message) => message,
+            SicilyError::Incompatible(message) => message,
+            SicilyError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for SicilyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SicilyError::Parse(message) => write!(f, "Parse error: {}", message),
+            SicilyError::UnknownVirtualNode(message) => write!(f, "Unknown virtual node: {}", message),
+            SicilyError::Routing(message) => write!(f, "Routing error: {}", message),
+            SicilyError::Timeout(message) => write!(f, "Timeout: {}", message),
+            SicilyError::Peer(message) => write!(f, "Peer error: {}", message),
+            SicilyError::Incompatible(message) => write!(f, "Incompatible peer: {}", message),
+            SicilyError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for SicilyError {}
+
+/*
+ * Plain messages are failures without a more specific variant. Callers that know
+ * better, like the request parsers, turn them into their own variant.
+ */
+impl From<String> for SicilyError {
+    fn from(message: String) -> Self {
+        SicilyError::Internal(message)
+    }
+}
+
+impl From<&str> for SicilyError {
+    fn from(message: &str) -> Self {
+        SicilyError::Internal(message.to_string())
+    }
+}
+
+/*
+ * I/O errors come from the connections to peers and clients.
+ */
+impl From<io::Error> for SicilyError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => SicilyError::Timeout(err.to_string()),
+            _ => SicilyError::Peer(err.to_string()),
+        }
+    }
+}
+
+impl From<ParseIntError> for SicilyError {
+    fn from(err: ParseIntError) -> Self {
+        SicilyError::Parse(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for SicilyError {
+    fn from(err: FromUtf8Error) -> Self {
+        SicilyError::Parse(err.to_string())
+    }
+}
\ No newline at end of file
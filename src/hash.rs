@@ -0,0 +1,160 @@
+/* 
+ * This file is part of the Sicily distribution (https://github.com/JeepYiheihou/sicily).
+ * Copyright (c) 2021 Jiachen Bai.
+ * 
+ * This program is free software: you can redistribute it and/or modify  
+ * it under the terms of the GNU General Public License as published by  
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU 
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License 
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+
+use num::bigint::BigUint;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::utils::Result;
+
+/*
+ * Hash function used to compute identifiers of locations and keys.
+ * Every node of a ring must use the same algorithm, otherwise they would
+ * disagree about who owns which key.
+ */
+pub trait HashAlgorithm: Send + Sync {
+    /*
+     * Name of the algorithm, as given in the config.
+     */
+    fn name(&self) -> &'static str;
+
+    /*
+     * Number of bits of the hash. Identifiers cannot have more bits than this.
+     */
+    fn bits(&self) -> u32;
+
+    /*
+     * Hash the input into a big uint.
+     */
+    fn hash(&self, input: &[u8]) -> BigUint;
+}
+
+impl fmt::Debug for dyn HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/*
+ * SHA-256, the default.
+ */
+pub struct Sha256Hash;
+
+impl HashAlgorithm for Sha256Hash {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn bits(&self) -> u32 {
+        256
+    }
+
+    fn hash(&self, input: &[u8]) -> BigUint {
+        use sha2::{ Sha256, Digest };
+        let mut hasher = Sha256::new();
+        hasher.update(input);
+        BigUint::from_bytes_be(&hasher.finalize())
+    }
+}
+
+/*
+ * SHA-1, as used by the original Chord paper and many other deployments.
+ */
+pub struct Sha1Hash;
+
+impl HashAlgorithm for Sha1Hash {
+    fn name(&self) -> &'static str {
+        "sha1"
+    }
+
+    fn bits(&self) -> u32 {
+        160
+    }
+
+    fn hash(&self, input: &[u8]) -> BigUint {
+        use sha1::{ Sha1, Digest };
+        let mut hasher = Sha1::new();
+        hasher.update(input);
+        BigUint::from_bytes_be(&hasher.finalize())
+    }
+}
+
+/*
+ * 64 bit FNV-1a. Much faster than the cryptographic hashes, but not meant to
+ * resist crafted inputs, and limited to 64 bit identifiers.
+ */
+pub struct Fnv1aHash;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+impl HashAlgorithm for Fnv1aHash {
+    fn name(&self) -> &'static str {
+        "fnv1a"
+    }
+
+    fn bits(&self) -> u32 {
+        64
+    }
+
+    fn hash(&self, input: &[u8]) -> BigUint {
+        let mut hash = FNV_OFFSET_BASIS;
+        for byte in input {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        BigUint::from(hash)
+    }
+}
+
+/*
+ * Get the built-in algorithm with the given name.
+ */
+pub fn from_name(name: &str) -> Result<Arc<dyn HashAlgorithm>> {
+    let algorithm: Arc<dyn HashAlgorithm> = match name.to_lowercase().as_str() {
+        "sha256" => Arc::new(Sha256Hash),
+        "sha1" => Arc::new(Sha1Hash),
+        "fnv1a" => Arc::new(Fnv1aHash),
+        _ => {
+            return Err(
+                format!("Invalid hash algorithm {}. Should be one of \"sha256\", \"sha1\" or \"fnv1a\".", name)
+                .into()
+            );
+        }
+    };
+    Ok(algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_hashes() {
+        assert_eq!(
+            format!("{}", Sha1Hash.hash(b"abc")),
+            BigUint::parse_bytes(b"a9993e364706816aba3e25717850c26c9cd0d89d", 16).unwrap().to_string()
+        );
+        assert_eq!(Fnv1aHash.hash(b""), BigUint::from(FNV_OFFSET_BASIS));
+        assert_eq!(Fnv1aHash.hash(b"a"), BigUint::from(0xaf63dc4c8601ec8cu64));
+        for name in ["sha256", "SHA1", "fnv1a"] {
+            assert_eq!(from_name(name).unwrap().name(), name.to_lowercase());
+        }
+        assert!(from_name("md5").is_err());
+    }
+}
\ No newline at end of file
@@ -17,12 +17,20 @@
 
 pub mod arithmetic;
 pub mod client;
+pub mod codec;
 pub mod command;
 pub mod config;
 pub mod constants;
+pub mod error;
+pub mod hash;
 pub mod location;
 pub mod server;
 pub mod membership;
+pub mod metrics;
 pub mod node;
+pub mod pool;
 pub mod process;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+
+#[cfg(test)]
+mod testing;
\ No newline at end of file
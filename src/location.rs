@@ -16,15 +16,17 @@
  */
 
 use num::bigint::BigUint;
-use std::net::{ IpAddr, SocketAddr };
+use std::fmt;
+use std::net::{ IpAddr, Ipv6Addr };
 use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::arithmetic;
 use crate::config::Config;
+use crate::hash::HashAlgorithm;
 use crate::utils::Result;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Location {
     pub ip: String,
     pub port: u16,
@@ -34,64 +36,86 @@ pub struct Location {
 
 impl Location {
     pub fn new(config: Arc<Config>, virtual_node_id: u8) -> Self {
-        let ip = config.host.clone();
-        let port = config.port;
-        let id_input = format!("{}:{}:{}", &ip, port, virtual_node_id);
-        let identifier = arithmetic::compute_identifier(config.id_bits as u32, &id_input);
-        return Self {
-            ip,
-            port,
-            virtual_node_id,
-            identifier,
-        }
+        let ip = canonical_host(&config.host);
+        let port = config.advertise_port;
+        Self::with_identifier(ip, port, virtual_node_id, config.hash.as_ref(), config.id_bits)
     }
 
+    /*
+     * Parse a location from "host:port:virtual_node_id", where the virtual node id
+     * defaults to 0 if absent. The host is either an IPv4 address, a hostname, or an
+     * IPv6 address in brackets, like "[::1]:8820:0".
+     */
     pub fn from_string(id_input: String, config: Arc<Config>) -> Result<Self> {
-        let arr: Vec<&str> = id_input.split(":").collect();
-        if arr.len() < 2 || arr.len() > 3 {
+        let (host, rest) = match id_input.strip_prefix('[') {
+            Some(stripped) => match stripped.split_once("]:") {
+                Some((host, rest)) => (host, rest),
+                None => {
+                    return Err("Invalid location. IPv6 host should be in brackets, like [::1]:8820:0.".into());
+                }
+            },
+            None => match id_input.split_once(':') {
+                Some((host, rest)) => (host, rest),
+                None => {
+                    return Err("Invalid number of params for making a location.".into());
+                }
+            },
+        };
+        let arr: Vec<&str> = rest.split(':').collect();
+        if host.is_empty() || arr.len() > 2 {
             return Err("Invalid number of params for making a location.".into());
         }
 
-        let ip = arr[0].to_string();
-        let port = arr[1].parse::<u16>()?;
-
-        let virtual_node_id;
-        if arr.len() == 2 {
-            virtual_node_id = 0;
+        let port = arr[0].parse::<u16>()?;
+        let virtual_node_id = if arr.len() == 1 {
+            0
         } else {
-            virtual_node_id = arr[2].parse::<u8>()?;
-        }
-        let identifier = arithmetic::compute_identifier(config.id_bits as u32, &id_input);
-        Ok(Self {
-            ip,
+            arr[1].parse::<u8>()?
+        };
+        Ok(Self::with_identifier(
+            canonical_host(host),
             port,
             virtual_node_id,
-            identifier,
-        })
+            config.hash.as_ref(),
+            config.id_bits,
+        ))
     }
 
-    pub fn to_string(&self) -> String {
-        return format!(
-            "{}:{}:{}",
-            self.ip,
-            self.port,
-            self.virtual_node_id
-        );
+    /*
+     * Build a location, with the identifier hashed from its canonical string form,
+     * so that every node computes the same identifier however the location was typed.
+     */
+    fn with_identifier(
+        ip: String,
+        port: u16,
+        virtual_node_id: u8,
+        hash: &dyn HashAlgorithm,
+        id_bits: u8,
+    ) -> Self {
+        let id_input = format!("{}:{}", join_host_port(&ip, port), virtual_node_id);
+        let identifier = arithmetic::compute_identifier(hash, id_bits as u32, &id_input);
+        Self {
+            ip,
+            port,
+            virtual_node_id,
+            identifier,
+        }
     }
 
     pub fn to_info(&self) -> String {
-        return format!(
-            "{}:{}:{}\r\n{} --> identifier",
-            self.ip,
-            self.port,
-            self.virtual_node_id,
+        format!(
+            "{}\r\n{} --> identifier",
+            self,
             self.identifier
-        );
+        )
     }
 
-    pub fn to_addr(&self) -> Result<SocketAddr> {
-        let addr = SocketAddr::new(IpAddr::from_str(&self.ip)?, self.port);
-        Ok(addr)
+    /*
+     * Get the "host:port" address of the location, which hostnames are resolved
+     * from when connecting.
+     */
+    pub fn to_addr(&self) -> String {
+        join_host_port(&self.ip, self.port)
     }
 
     /*
@@ -102,10 +126,10 @@ impl Location {
     pub fn option_to_result(option: &Option<Self>) -> Result<Self> {
         match option {
             Some(location) => {
-                return Ok(location.clone())
+                Ok(location.clone())
             },
             None => {
-                return Err("None error encoutered while trying to get something from Option.".into());
+                Err("None error encoutered while trying to get something from Option.".into())
             }
         }
     }
@@ -118,11 +142,110 @@ impl Location {
     pub fn print_info_from_option(option: &Option<Self>) -> String {
         match option {
             Some(location) => {
-                return location.to_info();
+                location.to_info()
             },
             None => {
-                return "None".to_string();
+                "None".to_string()
             }
         }
     }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.to_addr(),
+            self.virtual_node_id
+        )
+    }
+}
+
+/*
+ * Canonical form of a host: IP addresses are normalized, for example "0:0::1" becomes "::1",
+ * and hostnames are lowercased. Brackets around IPv6 addresses are dropped.
+ */
+pub fn canonical_host(host: &str) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match IpAddr::from_str(host) {
+        Ok(ip) => ip.to_string(),
+        Err(_) => host.to_lowercase(),
+    }
+}
+
+/*
+ * Join host and port into an address, with IPv6 hosts in brackets.
+ */
+pub fn join_host_port(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
+/*
+ * Split an address given as host or host:port. IPv6 hosts come either bare without
+ * a port, like "::1", or in brackets, like "[::1]:8820".
+ */
+pub fn split_host_port(input: &str) -> Result<(String, Option<u16>)> {
+    if Ipv6Addr::from_str(input).is_ok() {
+        return Ok((input.to_string(), None));
+    }
+    let (host, port) = match input.strip_prefix('[') {
+        Some(stripped) => match stripped.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, rest)) => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(format!("Invalid address {}.", input).into()),
+            },
+            None => return Err(format!("Invalid address {}. Missing closing bracket.", input).into()),
+        },
+        None => match input.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (input, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(format!("Invalid address {}. Host cannot be empty.", input).into());
+    }
+    let port = match port {
+        Some(port) => Some(
+            port.parse::<u16>()
+                .map_err(|e| format!("Invalid port in address {}: {}", input, e))?
+        ),
+        None => None,
+    };
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Sha256Hash;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("10.0.0.1").unwrap(), ("10.0.0.1".to_string(), None));
+        assert_eq!(split_host_port("10.0.0.1:8820").unwrap(), ("10.0.0.1".to_string(), Some(8820)));
+        assert_eq!(split_host_port("node-3.internal:8820").unwrap(), ("node-3.internal".to_string(), Some(8820)));
+        assert_eq!(split_host_port("::1").unwrap(), ("::1".to_string(), None));
+        assert_eq!(split_host_port("[::1]").unwrap(), ("::1".to_string(), None));
+        assert_eq!(split_host_port("[fe80::1]:8820").unwrap(), ("fe80::1".to_string(), Some(8820)));
+        assert!(split_host_port("[::1]8820").is_err());
+        assert!(split_host_port("[::1:8820").is_err());
+        assert!(split_host_port(":8820").is_err());
+    }
+
+    #[test]
+    fn test_ipv6_location_string() {
+        let location = Location::with_identifier(canonical_host("[0:0::1]"), 8820, 2, &Sha256Hash, 32);
+        assert_eq!(location.to_string(), "[::1]:8820:2");
+        assert_eq!(location.to_addr(), "[::1]:8820");
+        assert_eq!(canonical_host("Node-3.Internal"), "node-3.internal");
+        assert_eq!(
+            location.identifier,
+            arithmetic::compute_identifier(&Sha256Hash, 32, "[::1]:8820:2")
+        );
+    }
 }
\ No newline at end of file
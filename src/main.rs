@@ -17,18 +17,61 @@
 
 extern crate sicily;
 
-use pretty_env_logger;
-use std::sync::Arc;
-
 use sicily::utils;
-use sicily::node;
 use sicily::config;
-use sicily::server;
+use sicily::server::ServerBuilder;
 
-fn main() -> utils::Result<()>{
+#[tokio::main]
+async fn main() -> utils::Result<()>{
     pretty_env_logger::init();
-    let config = Arc::new(config::parse_params()?);
-    let node_list = Arc::new(node::NodeList::new(config.clone()));
-    server::start(node_list, config)?;
+    let config = config::parse_params()?;
+    /* Always show the effective config, whatever the log level, since it may come
+     * from the commandline, the environment and a config file at once. */
+    println!("Effective config: {:?}", config);
+    let server = ServerBuilder::new(config).start().await?;
+
+    /* Give up if the node cannot join the cluster, for example because its seeds
+     * belong to another one. */
+    let ready = tokio::select! {
+        ready = server.ready() => Some(ready),
+        _ = wait_for_shutdown_signal() => None,
+    };
+    match ready {
+        Some(Ok(())) => wait_for_shutdown_signal().await,
+        Some(Err(e)) => {
+            server.shutdown().await;
+            return Err(e);
+        },
+        None => {},
+    }
+
+    /* Leave the cluster gracefully on shutdown signal. */
+    log::info!("Received shutdown signal. Leaving the cluster.");
+    server.shutdown().await;
     Ok(())
+}
+
+/*
+ * Wait for ctrl-c, or SIGTERM on unix systems.
+ */
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{ signal, SignalKind };
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {},
+                }
+                return;
+            },
+            Err(e) => {
+                log::error!("Error installing SIGTERM handler. Error log: {}", e);
+            }
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::error!("Error waiting for ctrl-c. Error log: {}", e);
+    }
 }
\ No newline at end of file
@@ -15,15 +15,16 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
+use num::BigUint;
 use std::sync::Arc;
 use rand::prelude::*;
 
 use crate::arithmetic;
-use crate::client::Client;
 use crate::command::{ Request, Response };
 use crate::config::Config;
+use crate::error::SicilyError;
 use crate::location::Location;
-use crate::node::NodeList;
+use crate::node::{ NodeList, NodeState };
 use crate::process;
 use crate::utils::Result;
 
@@ -38,20 +39,213 @@ pub async fn join(
     config: Arc<Config>,
 ) -> Result<()> {
 
-    /* 1. Retrieve the local identifier of the node. */
+    /* 1. Make sure the local node can talk to the cluster: same protocol version and
+     * cluster name, and identifiers computed the same way. Otherwise the nodes would
+     * disagree about which node owns which key. */
+    if let Err(e) = process::hello(&location, node_list.clone(), config.clone()).await {
+        return Err(match e {
+            SicilyError::Incompatible(message) => {
+                SicilyError::Incompatible(format!("Cannot join {}. {}", location, message))
+            },
+            e => e,
+        });
+    }
+
+    /* 2. Retrieve the local identifier of the node. */
     let key = {
-        let node = node_list.node_list[virtual_node_id as usize].lock().await;
+        let node = node_list.node_list[virtual_node_id as usize].read().await;
         node.own_location().identifier
     };
 
-    /* 2. Based on the identifier, retrieve the successor. */
-    let successor = process::find_successor(&location, &key, config).await?;
+    /* 3. Based on the identifier, retrieve the successor. */
+    let successor = process::find_successor(&location, &key, node_list.clone(), config).await?;
 
-    /* 3. Update the node's metadata. */
+    /* 4. Update the node's metadata.
+     * The keys of the new range are pulled from the successor during stabilization,
+     * once the successor accepts the node as its predecessor. */
     {
-        let mut node = node_list.node_list[virtual_node_id as usize].lock().await;
+        let mut node = node_list.node_list[virtual_node_id as usize].write().await;
         node.set_predecessor(None);
         node.set_successor(Some(successor));
+        node.set_state(NodeState::Active);
+    }
+    Ok(())
+}
+
+/*
+ * Function called at startup to let every virtual node join the cluster through seeds.
+ * Each virtual node joins the first seed that is reachable, and keeps retrying until
+ * one of them succeeds. A seed that is the virtual node itself is skipped, so that the
+ * first node of a cluster can be given the same seeds as the others. A virtual node
+ * that is its own only seed stays the only node of its own ring.
+ * Retrying doesn't help with a seed the node is incompatible with, so the bootstrap
+ * fails right away with that error instead.
+ */
+pub async fn bootstrap(
+    seeds: Vec<Location>,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    for i in 0..config.virtual_node_number {
+        let local_location = {
+            let node = node_list.node_list[i as usize].read().await;
+            node.own_location()
+        };
+        let seeds: Vec<&Location> = seeds.iter()
+            .filter(|seed| **seed != local_location)
+            .collect();
+        if seeds.is_empty() {
+            continue;
+        }
+
+        let mut attempt: u32 = 0;
+        'retry: loop {
+            for seed in seeds.iter() {
+                match join(i, (*seed).clone(), node_list.clone(), config.clone()).await {
+                    Ok(()) => {
+                        log::info!("Virtual node id {} joined the cluster through {}.", i, seed);
+                        break 'retry;
+                    },
+                    Err(e) => {
+                        if let SicilyError::Incompatible(_) = e {
+                            return Err(e);
+                        }
+                        log::warn!(
+                            "Virtual node id {} could not join through seed {}. Error message: {}.",
+                            i,
+                            seed,
+                            e
+                        );
+                    }
+                }
+            }
+            attempt += 1;
+            log::warn!(
+                "Virtual node id {} could not join any seed after {} attempts. Retrying in {} ms.",
+                i,
+                attempt,
+                config.join_retry_interval
+            );
+            tokio::time::sleep(tokio::time::Duration::from_millis(config.join_retry_interval)).await;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Function called when a node leaves the cluster on purpose.
+ * The node stops accepting writes, hands its keys over to its successor, then tells the
+ * successor to adopt its predecessor, and the predecessor to adopt its successor. Finally
+ * the node is reset, hands over the keys it received in the meantime, and stops answering
+ * until it joins a cluster again. If the node fails to leave its ring, it accepts writes again.
+ */
+pub async fn leave(
+    virtual_node_id: u8,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+
+    /* 1. Retrieve the local state of the node, and stop accepting writes. The state only
+     * changes once nothing can fail any more before leave_ring(). */
+    let (local_location, predecessor, successor, entries) = {
+        let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+        if node.get_state() == NodeState::Left {
+            return Ok(());
+        }
+        let successor = node.get_successor()?;
+        node.set_state(NodeState::Leaving);
+        (
+            node.own_location(),
+            node.get_predecessor().ok(),
+            successor,
+            node.get_values(),
+        )
+    };
+
+    if successor != local_location {
+        if let Err(e) = leave_ring(
+            &local_location,
+            predecessor,
+            &successor,
+            entries,
+            node_list.clone(),
+            config.clone(),
+        ).await {
+            let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+            node.set_state(NodeState::Active);
+            return Err(e);
+        }
+    }
+
+    /* 5. Reset the node's metadata. From now on the node rejects writes and handoffs,
+     * so whatever is left in its store can be handed over for good. Those are keys
+     * handed off by a predecessor that was leaving at the same time. */
+    let remaining = {
+        let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+        node.reset();
+        node.set_state(NodeState::Left);
+        match successor != local_location {
+            true => node.take_values(),
+            false => Vec::new(),
+        }
+    };
+
+    /* 6. Hand the remaining keys over to the successor. */
+    if !remaining.is_empty() {
+        if let Err(e) = process::handoff(&successor, remaining.clone(), node_list.clone(), config).await {
+            let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+            for (key, value) in remaining {
+                node.put_value(key, value);
+            }
+            return Err(e);
+        }
+    }
+    log::info!("Virtual node id {} left the cluster.", virtual_node_id);
+    Ok(())
+}
+
+/*
+ * Hand the keys and the range of a leaving node over to its neighbours.
+ * This function is a part of leave().
+ */
+async fn leave_ring(
+    local_location: &Location,
+    predecessor: Option<Location>,
+    successor: &Location,
+    entries: Vec<(BigUint, String)>,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    /* 2. Hand the keys over to the successor, which becomes their owner. */
+    if !entries.is_empty() {
+        process::handoff(successor, entries.clone(), node_list.clone(), config.clone()).await?;
+    }
+    {
+        let mut node = node_list.node_list[local_location.virtual_node_id as usize].write().await;
+        for (key, value) in entries.iter() {
+            node.delete_value_if_unchanged(key, value);
+        }
+    }
+
+    /* 3. Tell the successor to adopt the predecessor. */
+    let predecessor = predecessor.filter(|predecessor| predecessor != local_location);
+    process::replace_predecessor(
+        successor,
+        local_location,
+        predecessor.clone(),
+        node_list.clone(),
+        config.clone(),
+    ).await?;
+
+    /* 4. Tell the predecessor to adopt the successor. */
+    if let Some(predecessor) = predecessor {
+        process::replace_successor(
+            &predecessor,
+            local_location,
+            successor,
+            node_list,
+            config,
+        ).await?;
     }
     Ok(())
 }
@@ -64,13 +258,39 @@ pub async fn stablize(
     node_list: Arc<NodeList>,
     config: Arc<Config>,
 ) -> Result<()> {
-    let (mut successor, local_location) = {
-        let node = node_list.node_list[virtual_node_id as usize].lock().await;
-        let successor = node.get_successor()?;
-        (successor, node.own_location())
+    /* 1. Find the first live successor. If the successor stops answering, fail over
+     * to the next entry of the successor list. */
+    let (mut successor, local_location, option) = loop {
+        let (successor, local_location) = {
+            let node = node_list.node_list[virtual_node_id as usize].read().await;
+            let successor = node.get_successor()?;
+            (successor, node.own_location())
+        };
+
+        match process::get_predecessor(&successor, node_list.clone(), config.clone()).await {
+            Ok(option) => break (successor, local_location, option),
+            Err(e) => {
+                /* There is nothing to fail over to if the node cannot even talk to itself. */
+                if successor == local_location {
+                    return Err(e);
+                }
+                let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                let new_successor = node.remove_successor(&successor);
+                log::warn!(
+                    "Successor {} of virtual node id {} is unreachable, failing over to {}. Error message: {}.",
+                    successor,
+                    virtual_node_id,
+                    new_successor,
+                    e
+                );
+            }
+        }
     };
 
-    let option = process::get_predecessor(&successor, config.clone()).await?;
+    /* 2. Check whether a new node joined between the local node and the successor.
+     * The candidate is only adopted if it answers, so that a crashed node that is still
+     * recorded as predecessor of the successor does not take over the successor again. */
+    let mut list = None;
     match option {
         Some(location) => {
             let predecessor_of_successor = location;
@@ -78,11 +298,24 @@ pub async fn stablize(
                 &predecessor_of_successor.identifier,
                 (&local_location.identifier, false),
                 (&successor.identifier, false)) {
-                {
-                    let mut node = node_list.node_list[virtual_node_id as usize].lock().await;
-                    node.set_successor(Some(predecessor_of_successor.clone()));
+                match process::get_successor_list(&predecessor_of_successor, node_list.clone(), config.clone()).await {
+                    Ok(candidate_list) => {
+                        {
+                            let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+                            node.set_successor(Some(predecessor_of_successor.clone()));
+                        }
+                        successor = predecessor_of_successor.clone();
+                        list = Some(candidate_list);
+                    },
+                    Err(e) => {
+                        log::debug!(
+                            "Ignoring unreachable predecessor {} of successor {}. Error message: {}.",
+                            predecessor_of_successor,
+                            successor,
+                            e
+                        );
+                    }
                 }
-                successor = predecessor_of_successor.clone();
             }
         },
         None => {
@@ -92,16 +325,39 @@ pub async fn stablize(
              * - Its predecessor (the caller node of this function) keeps doing the get_predecessor() to it.
              * - At the very moment, the successor node tries to join some other node, maybe in another cluster.
              * - Thus the predecessor field of the successor node is marked as None.
-             * - Therefore we get into this scenario. */
-            return Ok(())
+             * - Therefore we get into this scenario.
+             * It can also be due to check_predecessor() on the successor node having cleared
+             * a failed predecessor. In both cases we still notify the successor, so that
+             * it can adopt the local node as its new predecessor. */
         }
     };
 
-    notify(
+    /* 3. Refresh the successor list from the successor. */
+    let list = match list {
+        Some(list) => list,
+        None => process::get_successor_list(&successor, node_list.clone(), config.clone()).await?,
+    };
+    {
+        let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+        node.update_successor_list(&successor, list);
+    }
+
+    /* 4. Notify the successor about the local node. */
+    let is_predecessor = notify(
         local_location,
-        successor,
-        config,
+        successor.clone(),
+        node_list.clone(),
+        config.clone(),
     ).await?;
+
+    /* 5. Once the successor has the local node as its predecessor, it doesn't own the keys
+     * in (successor, local] any more. Pull the ones it still holds over. This is done on
+     * every round rather than only when the successor accepts the local node, so that a
+     * transfer that failed half way, or a write that landed late on the successor, is
+     * picked up by the next round. */
+    if is_predecessor {
+        pull_keys(virtual_node_id, successor, node_list, config).await?;
+    }
     Ok(())
 }
 
@@ -109,29 +365,120 @@ pub async fn stablize(
  * Notify a supposedly successor (target_location) of a given node (local_location).
  * The successor, after receiving the notification, will make a decision whether
  * it needs to update its predecessor pointer to the local_location.
+ * Return whether local_location is the predecessor of the successor after the notification.
  */
 async fn notify(
     local_location: Location,
     target_location: Location,
+    node_list: Arc<NodeList>,
     config: Arc<Config>,
-) -> Result<()> {
+) -> Result<bool> {
     let request = Request::Notify {
         virtual_node_id: target_location.virtual_node_id,
         notifier: local_location,
     };
-    let mut client = Client::new(&target_location).await?;
-    client.send_request(request).await?;
-    let response = client.receive(config).await?;
-    match response {
-        Response::Notify => {
-            /* Happy case, nothing to do. */
-        },
+    let response = process::call(&target_location, request, node_list, config).await?;
+    let accepted = match response {
+        Response::Notify { accepted } => accepted,
         _ => {
             return Err(
                 "Error receiving response while doing NOTIFY. Got unexpected response type."
                 .into()
             );
         }
+    };
+    Ok(accepted)
+}
+
+/*
+ * Pull the keys the local node has become the owner of from its successor.
+ * The successor still holds every key that is not in range (local, successor] any more,
+ * that is range (successor, local]. The keys are stored locally first, and only then
+ * the successor is told to delete its copy. A key overwritten on the successor in between
+ * stays there until the next pull. A key already stored locally was written after the
+ * local node became its owner, so it is newer than the copy of the successor and is kept.
+ */
+async fn pull_keys(
+    virtual_node_id: u8,
+    successor: Location,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let local_location = {
+        let node = node_list.node_list[virtual_node_id as usize].read().await;
+        node.own_location()
+    };
+    if successor == local_location {
+        return Ok(());
+    }
+
+    /* 1. Retrieve the keys from the successor. */
+    let entries = process::transfer(
+        &successor,
+        &successor.identifier,
+        &local_location.identifier,
+        node_list.clone(),
+        config.clone(),
+    ).await?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    /* 2. Store the keys locally, without overwriting newer writes. */
+    {
+        let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+        for (key, value) in entries.iter() {
+            node.put_value_if_absent(key.clone(), value.clone());
+        }
+    }
+
+    /* 3. Confirm the transfer so that the successor deletes its copy. */
+    let number = entries.len();
+    process::confirm_transfer(&successor, entries, node_list, config).await?;
+    log::info!(
+        "Virtual node id {} took over {} keys from {}.",
+        virtual_node_id,
+        number,
+        successor
+    );
+    Ok(())
+}
+
+/*
+ * Periodic function to check whether the predecessor is still alive.
+ * If the predecessor does not answer, it is cleared, so that the node can accept
+ * a new predecessor on the next notification.
+ */
+pub async fn check_predecessor(
+    virtual_node_id: u8,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let (predecessor, local_location) = {
+        let node = node_list.node_list[virtual_node_id as usize].read().await;
+        (node.get_predecessor().ok(), node.own_location())
+    };
+
+    let predecessor = match predecessor {
+        Some(predecessor) if predecessor != local_location => predecessor,
+        _ => {
+            /* Nothing to check if there is no predecessor, or the node is its own predecessor. */
+            return Ok(());
+        }
+    };
+
+    if let Err(e) = process::ping(&predecessor, node_list.clone(), config).await {
+        let mut node = node_list.node_list[virtual_node_id as usize].write().await;
+        /* Only clear the predecessor if it hasn't been changed while we were probing. */
+        if node.get_predecessor().ok().as_ref() == Some(&predecessor) {
+            node.set_predecessor(None);
+            log::warn!(
+                "Predecessor {} of virtual node id {} is unreachable, clearing it. Error message: {}.",
+                predecessor,
+                virtual_node_id,
+                e
+            );
+        }
     }
     Ok(())
 }
@@ -148,7 +495,7 @@ pub async fn fix_fingers(
     /* 1. Pick a random finger to fix. */
     let (index, start_identifier, local_location) = {
         let mut rng = rand::rngs::StdRng::from_entropy();
-        let node = node_list.node_list[virtual_node_id as usize].lock().await;
+        let node = node_list.node_list[virtual_node_id as usize].read().await;
         let index = rng.gen_range(1..node.get_finger_len());
         let start_identifier = node.get_finger_start_identifier(index)?;
         let local_location = node.own_location();
@@ -156,12 +503,209 @@ pub async fn fix_fingers(
     };
 
     /* 2. Communicate with the cluster. */
-    let successor = process::find_successor(&local_location, &start_identifier, config).await?;
+    let successor = process::find_successor(&local_location, &start_identifier, node_list.clone(), config).await?;
 
     /* 3. Update the finger. */
     {
-        let mut node = node_list.node_list[virtual_node_id as usize].lock().await;
+        let mut node = node_list.node_list[virtual_node_id as usize].write().await;
         node.set_finger(index, Some(successor))?;
     }
     Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command;
+    use crate::testing::{ self, get, put_keys, request, ring, stabilize_rounds, stored };
+
+    #[tokio::test]
+    async fn test_join_hands_keys_over() {
+        let (node_list, config) = testing::node_list(2, &[]);
+        let first = Location::new(config.clone(), 0);
+        let second = Location::new(config.clone(), 1);
+
+        /* Virtual node 0 owns every key until virtual node 1 joins it. */
+        let keys = put_keys(&node_list, &config, 0).await;
+
+        join(1, first.clone(), node_list.clone(), config.clone()).await.unwrap();
+        stabilize_rounds(&node_list, &config, 3).await;
+
+        let moved = |key: &BigUint| {
+            arithmetic::is_in_range(key, (&first.identifier, false), (&second.identifier, true))
+        };
+        assert!(keys.iter().any(moved));
+        for key in keys.iter() {
+            let (owner, other) = if moved(key) { (1, 0) } else { (0, 1) };
+            assert_eq!(stored(&node_list, owner, key).await, Some(format!("value-{}", key)));
+            assert_eq!(stored(&node_list, other, key).await, None);
+            for i in 0..2 {
+                assert_eq!(get(&node_list, &config, i, key).await, Some(format!("value-{}", key)));
+            }
+        }
+
+        /* A key virtual node 0 still holds and virtual node 1 lacks, for example because
+         * a transfer failed half way, is pulled by the next round. The confirmation only
+         * deletes it on virtual node 0 if it hasn't been overwritten in between. */
+        let key = keys.iter().find(|key| moved(key)).unwrap().clone();
+        node_list.node_list[1].write().await.delete_value(&key);
+        {
+            let mut node = node_list.node_list[0].write().await;
+            node.put_value(key.clone(), "stranded".to_string());
+        }
+        request(&node_list, &config, Request::ConfirmTransfer {
+            virtual_node_id: 0,
+            entries: vec![(key.clone(), "stale".to_string())],
+        }).await;
+        assert_eq!(stored(&node_list, 0, &key).await, Some("stranded".to_string()));
+
+        stabilize_rounds(&node_list, &config, 1).await;
+        assert_eq!(stored(&node_list, 0, &key).await, None);
+        assert_eq!(stored(&node_list, 1, &key).await, Some("stranded".to_string()));
+        assert_eq!(get(&node_list, &config, 0, &key).await, Some("stranded".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_pulled_keys_do_not_overwrite_newer_writes() {
+        let (node_list, config) = ring(2).await;
+        let first = Location::new(config.clone(), 0);
+        let second = Location::new(config.clone(), 1);
+        let keys = put_keys(&node_list, &config, 0).await;
+        let key = keys.iter()
+            .find(|key| arithmetic::is_in_range(key, (&first.identifier, false), (&second.identifier, true)))
+            .unwrap()
+            .clone();
+
+        /* Virtual node 0 still holds a copy of a key virtual node 1 owns, as between TRANSFER
+         * and CONFIRMTRANSFER, while the key is written again on virtual node 1. */
+        node_list.node_list[0].write().await.put_value(key.clone(), "stale".to_string());
+        request(&node_list, &config, Request::Put {
+            virtual_node_id: 1,
+            key: key.clone(),
+            value: "fresh".to_string(),
+            forwarded: false,
+        }).await;
+
+        stabilize_rounds(&node_list, &config, 1).await;
+        assert_eq!(stored(&node_list, 1, &key).await, Some("fresh".to_string()));
+        assert_eq!(stored(&node_list, 0, &key).await, None);
+        for i in 0..2 {
+            assert_eq!(get(&node_list, &config, i, &key).await, Some("fresh".to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_leave_hands_keys_over_and_stops_answering() {
+        let (node_list, config) = ring(3).await;
+        let keys = put_keys(&node_list, &config, 0).await;
+        assert!(!node_list.node_list[1].read().await.get_values().is_empty());
+
+        /* A leaving node rejects writes to the keys it still owns. */
+        let owned = node_list.node_list[1].read().await.get_values()[0].0.clone();
+        node_list.node_list[1].write().await.set_state(NodeState::Leaving);
+        let result = command::execute_local_request(Request::Put {
+            virtual_node_id: 1,
+            key: owned,
+            value: "lost".to_string(),
+            forwarded: false,
+        }, node_list.clone(), config.clone()).await;
+        assert!(matches!(result.unwrap_err(), SicilyError::Peer(_)));
+        node_list.node_list[1].write().await.set_state(NodeState::Active);
+
+        leave(1, node_list.clone(), config.clone()).await.unwrap();
+        assert!(node_list.node_list[1].read().await.get_values().is_empty());
+        stabilize_rounds(&node_list, &config, 2).await;
+
+        for key in keys.iter() {
+            for i in [0, 2] {
+                assert_eq!(get(&node_list, &config, i, key).await, Some(format!("value-{}", key)));
+            }
+        }
+
+        /* The node that left doesn't answer lookups as the only node of its own ring. */
+        let result = command::execute_local_request(Request::FindSuccessor {
+            virtual_node_id: 1,
+            key: keys[0].clone(),
+            path: Vec::new(),
+        }, node_list.clone(), config.clone()).await;
+        assert!(matches!(result.unwrap_err(), SicilyError::Peer(_)));
+    }
+
+    #[tokio::test]
+    async fn test_leave_fails_with_unreachable_successor_and_accepts_writes_again() {
+        let (node_list, config) = ring(3).await;
+        put_keys(&node_list, &config, 0).await;
+        let (owned, value) = node_list.node_list[1].read().await.get_values()[0].clone();
+
+        /* Nothing listens on port 1, as if the successor had crashed. */
+        let dead = Location::from_string("127.0.0.1:1:0".to_string(), config.clone()).unwrap();
+        node_list.node_list[1].write().await.set_successor(Some(dead));
+        assert!(leave(1, node_list.clone(), config.clone()).await.is_err());
+
+        let node = node_list.node_list[1].read().await;
+        assert_eq!(node.get_state(), NodeState::Active);
+        assert_eq!(node.get_value(&owned), Some(value));
+        drop(node);
+        request(&node_list, &config, Request::Put {
+            virtual_node_id: 1,
+            key: owned.clone(),
+            value: "written".to_string(),
+            forwarded: false,
+        }).await;
+        assert_eq!(stored(&node_list, 1, &owned).await, Some("written".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_check_predecessor_clears_dead_predecessor_and_ring_heals() {
+        let (node_list, config) = ring(3).await;
+        let keys = put_keys(&node_list, &config, 0).await;
+
+        /* Nothing listens on port 1, as if the predecessor had crashed. */
+        let predecessor = node_list.node_list[1].read().await.get_predecessor().unwrap();
+        let dead = Location::from_string("127.0.0.1:1:0".to_string(), config.clone()).unwrap();
+        node_list.node_list[1].write().await.set_predecessor(Some(dead));
+        check_predecessor(1, node_list.clone(), config.clone()).await.unwrap();
+        assert!(node_list.node_list[1].read().await.get_predecessor().is_err());
+
+        /* The live predecessor notifies the node again on the next round. */
+        stabilize_rounds(&node_list, &config, 1).await;
+        assert_eq!(node_list.node_list[1].read().await.get_predecessor().unwrap(), predecessor);
+        for key in keys.iter() {
+            for i in 0..3 {
+                assert_eq!(get(&node_list, &config, i, key).await, Some(format!("value-{}", key)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_key_finds_the_owner_of_its_identifier() {
+        let (node_list, config) = ring(3).await;
+        for key in ["apple", "banana", "cherry", "durian", "elderberry"] {
+            let identifier = arithmetic::compute_identifier(config.hash.as_ref(), config.id_bits as u32, key);
+            request(&node_list, &config, Request::Put {
+                virtual_node_id: 0,
+                key: identifier.clone(),
+                value: key.to_string(),
+                forwarded: false,
+            }).await;
+
+            /* Every virtual node resolves the key to the node storing it. */
+            for i in 0..3 {
+                let response = request(&node_list, &config, Request::LookupKey {
+                    virtual_node_id: i,
+                    key: key.to_string(),
+                }).await;
+                let location = match response {
+                    Response::LookupKey { identifier: res_identifier, location } => {
+                        assert_eq!(res_identifier, identifier);
+                        location
+                    },
+                    other => panic!("Unexpected response {:?}", other),
+                };
+                let owner = node_list.node_list[location.virtual_node_id as usize].read().await;
+                assert!(owner.is_responsible_for(&identifier));
+                assert_eq!(owner.get_value(&identifier), Some(key.to_string()));
+            }
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,370 @@
+/* 
+ * This file is part of the Sicily distribution (https://github.com/JeepYiheihou/sicily).
+ * Copyright (c) 2021 Jiachen Bai.
+ * 
+ * This program is free software: you can redistribute it and/or modify  
+ * it under the terms of the GNU General Public License as published by  
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU 
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License 
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+
+use bytes::BytesMut;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicI64, AtomicU64, Ordering };
+use std::time::Duration;
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ TcpListener, TcpStream };
+
+use crate::node::NodeList;
+use crate::pool::ConnectionPool;
+
+/* Upper bounds of the request latency buckets, in seconds. */
+const LATENCY_BUCKETS: [f64; 13] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/* Upper bounds of the lookup hop count buckets. */
+const HOP_BUCKETS: [f64; 10] = [1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0, 16.0, 32.0, 64.0];
+
+/* Largest HTTP request header we accept on the metrics endpoint. */
+const MAX_HTTP_REQUEST_SIZE: usize = 8192;
+
+/*
+ * Cumulative histogram, as Prometheus expects it.
+ */
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, bound) in self.bounds.iter().enumerate() {
+            if value <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, bucket);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+/*
+ * Counters of a periodic task.
+ */
+#[derive(Debug, Default)]
+struct Outcomes {
+    success: AtomicU64,
+    failure: AtomicU64,
+}
+
+impl Outcomes {
+    fn record(&self, success: bool) {
+        match success {
+            true => self.success.fetch_add(1, Ordering::Relaxed),
+            false => self.failure.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/*
+ * Metrics of one request type.
+ */
+#[derive(Debug)]
+struct RequestMetrics {
+    errors: u64,
+    latency: Histogram,
+}
+
+/*
+ * Metrics of the node, rendered in the Prometheus text format. Every server has its
+ * own, held by its NodeList.
+ * Counters updated on hot paths are atomics. The labelled ones live behind a mutex,
+ * which is only held for the duration of an update.
+ */
+#[derive(Debug)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<&'static str, RequestMetrics>>,
+    lookup_hops: Mutex<Histogram>,
+    stabilize: Outcomes,
+    fix_fingers: Outcomes,
+    check_predecessor: Outcomes,
+    inbound_connections: AtomicI64,
+    outbound_connections: AtomicI64,
+    predecessor_changes: Mutex<BTreeMap<u8, u64>>,
+    successor_changes: Mutex<BTreeMap<u8, u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            lookup_hops: Mutex::new(Histogram::new(&HOP_BUCKETS)),
+            stabilize: Outcomes::default(),
+            fix_fingers: Outcomes::default(),
+            check_predecessor: Outcomes::default(),
+            inbound_connections: AtomicI64::new(0),
+            outbound_connections: AtomicI64::new(0),
+            predecessor_changes: Mutex::new(BTreeMap::new()),
+            successor_changes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /*
+     * Record a request served by the node, named after its command.
+     */
+    pub fn record_request(&self, name: &'static str, latency: Duration, success: bool) {
+        let mut requests = self.requests.lock().unwrap();
+        let request = requests.entry(name).or_insert_with(|| RequestMetrics {
+            errors: 0,
+            latency: Histogram::new(&LATENCY_BUCKETS),
+        });
+        if !success {
+            request.errors += 1;
+        }
+        request.latency.observe(latency.as_secs_f64());
+    }
+
+    /*
+     * Record the number of nodes an iterative lookup visited.
+     */
+    pub fn record_lookup_hops(&self, hops: usize) {
+        self.lookup_hops.lock().unwrap().observe(hops as f64);
+    }
+
+    pub fn record_stabilize(&self, success: bool) {
+        self.stabilize.record(success);
+    }
+
+    pub fn record_fix_fingers(&self, success: bool) {
+        self.fix_fingers.record(success);
+    }
+
+    pub fn record_check_predecessor(&self, success: bool) {
+        self.check_predecessor.record(success);
+    }
+
+    /*
+     * Track connections accepted from clients or other nodes.
+     */
+    pub fn inbound_connection_opened(&self) {
+        self.inbound_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inbound_connection_closed(&self) {
+        self.inbound_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /*
+     * Track connections opened to other nodes.
+     */
+    pub fn outbound_connection_opened(&self) {
+        self.outbound_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn outbound_connection_closed(&self) {
+        self.outbound_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_predecessor_change(&self, virtual_node_id: u8) {
+        *self.predecessor_changes.lock().unwrap().entry(virtual_node_id).or_insert(0) += 1;
+    }
+
+    pub fn record_successor_change(&self, virtual_node_id: u8) {
+        *self.successor_changes.lock().unwrap().entry(virtual_node_id).or_insert(0) += 1;
+    }
+
+    /*
+     * Render every metric in the Prometheus text exposition format, along with the
+     * size of the connection pool of the same server.
+     */
+    pub fn render(&self, pool: &ConnectionPool) -> String {
+        let mut out = String::new();
+
+        {
+            let requests = self.requests.lock().unwrap();
+            let _ = writeln!(out, "# HELP sicily_requests_total Requests served, by command.");
+            let _ = writeln!(out, "# TYPE sicily_requests_total counter");
+            for (name, request) in requests.iter() {
+                let _ = writeln!(out, "sicily_requests_total{{request=\"{}\"}} {}", name, request.latency.count);
+            }
+            let _ = writeln!(out, "# HELP sicily_request_errors_total Requests that failed, by command.");
+            let _ = writeln!(out, "# TYPE sicily_request_errors_total counter");
+            for (name, request) in requests.iter() {
+                let _ = writeln!(out, "sicily_request_errors_total{{request=\"{}\"}} {}", name, request.errors);
+            }
+            let _ = writeln!(out, "# HELP sicily_request_duration_seconds Time spent serving requests, by command.");
+            let _ = writeln!(out, "# TYPE sicily_request_duration_seconds histogram");
+            for (name, request) in requests.iter() {
+                let labels = format!("request=\"{}\"", name);
+                request.latency.render(&mut out, "sicily_request_duration_seconds", &labels);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP sicily_lookup_hops Number of nodes visited by iterative lookups.");
+        let _ = writeln!(out, "# TYPE sicily_lookup_hops histogram");
+        self.lookup_hops.lock().unwrap().render(&mut out, "sicily_lookup_hops", "");
+
+        for (name, help, outcomes) in [
+            ("sicily_stabilize_total", "Runs of stabilize, by result.", &self.stabilize),
+            ("sicily_fix_fingers_total", "Runs of fix_fingers, by result.", &self.fix_fingers),
+            ("sicily_check_predecessor_total", "Runs of check_predecessor, by result.", &self.check_predecessor),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{}{{result=\"success\"}} {}", name, outcomes.success.load(Ordering::Relaxed));
+            let _ = writeln!(out, "{}{{result=\"failure\"}} {}", name, outcomes.failure.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(out, "# HELP sicily_open_connections Open connections, by direction.");
+        let _ = writeln!(out, "# TYPE sicily_open_connections gauge");
+        let _ = writeln!(out, "sicily_open_connections{{direction=\"inbound\"}} {}", self.inbound_connections.load(Ordering::Relaxed));
+        let _ = writeln!(out, "sicily_open_connections{{direction=\"outbound\"}} {}", self.outbound_connections.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP sicily_pool_idle_connections Idle connections to other nodes kept in the pool.");
+        let _ = writeln!(out, "# TYPE sicily_pool_idle_connections gauge");
+        let _ = writeln!(out, "sicily_pool_idle_connections {}", pool.idle_len());
+
+        for (name, help, changes) in [
+            ("sicily_predecessor_changes_total", "Changes of the predecessor, by virtual node.", &self.predecessor_changes),
+            ("sicily_successor_changes_total", "Changes of the successor, by virtual node.", &self.successor_changes),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (virtual_node_id, count) in changes.lock().unwrap().iter() {
+                let _ = writeln!(out, "{}{{vnode=\"{}\"}} {}", name, virtual_node_id, count);
+            }
+        }
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+ * Serve the metrics over HTTP at /metrics on the given address.
+ * Only the bare minimum of HTTP/1.1 is spoken: one GET request per connection.
+ */
+pub async fn serve(addr: String, node_list: Arc<NodeList>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Error initializing metrics listener. Error log: {}", e);
+            return;
+        }
+    };
+    log::info!("Serving metrics at http://{}/metrics", addr);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::error!("Error accepting metrics connection. Error log: {}", e);
+                continue;
+            }
+        };
+        let node_list = node_list.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, &node_list).await {
+                log::debug!("Error serving metrics. Error log: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_http(mut stream: TcpStream, node_list: &NodeList) -> std::io::Result<()> {
+    /* Read until the end of the request header. */
+    let mut buf = BytesMut::with_capacity(1024);
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        if buf.len() > MAX_HTTP_REQUEST_SIZE || stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
+
+    let header = String::from_utf8_lossy(&buf);
+    let mut request_line = header.lines().next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", node_list.metrics.render(&node_list.pool)),
+        ("GET", _) => ("404 Not Found", "Not found. Metrics are served at /metrics.\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported.\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_is_cumulative() {
+        let mut histogram = Histogram::new(&HOP_BUCKETS);
+        histogram.observe(1.0);
+        histogram.observe(3.0);
+        histogram.observe(100.0);
+        let mut out = String::new();
+        histogram.render(&mut out, "hops", "");
+        assert!(out.contains("hops_bucket{le=\"1\"} 1\n"));
+        assert!(out.contains("hops_bucket{le=\"4\"} 2\n"));
+        assert!(out.contains("hops_bucket{le=\"64\"} 2\n"));
+        assert!(out.contains("hops_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("hops_count 3\n"));
+    }
+
+    #[test]
+    fn test_render_request_metrics() {
+        let metrics = Metrics::new();
+        metrics.record_request("get", Duration::from_millis(2), true);
+        metrics.record_request("get", Duration::from_millis(20), false);
+        metrics.record_successor_change(3);
+        let out = metrics.render(&ConnectionPool::new());
+        assert!(out.contains("sicily_requests_total{request=\"get\"} 2\n"));
+        assert!(out.contains("sicily_request_errors_total{request=\"get\"} 1\n"));
+        assert!(out.contains("sicily_request_duration_seconds_bucket{request=\"get\",le=\"0.0025\"} 1\n"));
+        assert!(out.contains("sicily_successor_changes_total{vnode=\"3\"} 1\n"));
+    }
+}
\ No newline at end of file
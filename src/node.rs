@@ -16,12 +16,18 @@
  */
 
 use num::bigint::BigUint;
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use std::ops::Bound::{ Excluded, Included, Unbounded };
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 
 use crate::arithmetic;
 use crate::config::Config;
+use crate::error::SicilyError;
 use crate::location::Location;
+use crate::metrics::Metrics;
+use crate::pool::ConnectionPool;
 use crate::utils::Result;
 
 #[derive(Debug)]
@@ -30,6 +36,63 @@ pub struct Node {
     predecessor: Option<Location>,
     finger: Vec<Option<Location>>,
     finger_start_identifier: Vec<BigUint>,
+    successor_list: Vec<Location>,
+    successor_list_len: usize,
+    store: BTreeMap<BigUint, String>,
+    state: NodeState,
+    metrics: Arc<Metrics>,
+}
+
+/*
+ * Whether a node takes part in its cluster.
+ * - Active: the node owns its range, as right after start or after joining.
+ * - Leaving: the node is handing its keys over to its successor, and rejects writes.
+ * - Left: the node has left its cluster, and owns no range until it joins again.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Active,
+    Leaving,
+    Left,
+}
+
+/*
+ * Snapshot of the state of a node, meant to be serialized for tooling.
+ * Identifiers are decimal strings, since they can be larger than JSON numbers.
+ */
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub location: LocationInfo,
+    pub predecessor: Option<LocationInfo>,
+    pub successor: Option<LocationInfo>,
+    pub successor_list: Vec<LocationInfo>,
+    pub stored_keys: usize,
+    pub fingers: Vec<FingerInfo>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocationInfo {
+    pub host: String,
+    pub port: u16,
+    pub virtual_node_id: u8,
+    pub identifier: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FingerInfo {
+    pub start: String,
+    pub location: Option<LocationInfo>,
+}
+
+impl From<&Location> for LocationInfo {
+    fn from(location: &Location) -> Self {
+        Self {
+            host: location.ip.clone(),
+            port: location.port,
+            virtual_node_id: location.virtual_node_id,
+            identifier: location.identifier.to_string(),
+        }
+    }
 }
 
 /*
@@ -44,6 +107,7 @@ impl Node {
     pub fn new(
         config: Arc<Config>,
         virtual_node_id: u8,
+        metrics: Arc<Metrics>,
     ) -> Self {
         let location = Location::new(config.clone(), virtual_node_id);
         let predecessor = Some(location.clone());
@@ -68,6 +132,11 @@ impl Node {
             predecessor,
             finger,
             finger_start_identifier,
+            successor_list: Vec::new(),
+            successor_list_len: config.successor_list_len as usize,
+            store: BTreeMap::new(),
+            state: NodeState::Active,
+            metrics,
         }
     }
 
@@ -90,7 +159,75 @@ impl Node {
      * Set the location of successor.
      */
     pub fn set_successor(&mut self, successor: Option<Location>) {
-        self.finger[0] = successor;
+        if let Some(location) = &successor {
+            if self.successor_list.first() != Some(location) && *location != self.location {
+                self.successor_list.retain(|entry| entry != location);
+                self.successor_list.insert(0, location.clone());
+                self.successor_list.truncate(self.successor_list_len);
+            }
+        }
+        self.update_successor(successor);
+    }
+
+    /*
+     * Get the successor list, nearest successor first.
+     */
+    pub fn get_successor_list(&self) -> Vec<Location> {
+        self.successor_list.clone()
+    }
+
+    /*
+     * Refresh the successor list from the successor list of our successor:
+     * our list is the successor itself followed by its own successors.
+     * The list stops once it wraps around to the local node.
+     */
+    pub fn update_successor_list(&mut self, successor: &Location, list: Vec<Location>) {
+        let mut successor_list = Vec::new();
+        for location in std::iter::once(successor.clone()).chain(list) {
+            if location == self.location || successor_list.len() >= self.successor_list_len {
+                break;
+            }
+            if !successor_list.contains(&location) {
+                successor_list.push(location);
+            }
+        }
+        self.successor_list = successor_list;
+    }
+
+    /*
+     * Drop a successor which has been detected as failed, and fail over to
+     * the next entry of the successor list. Fingers pointing to the failed
+     * node are reset to the local location so that lookups skip them until
+     * fix_fingers repairs them.
+     * If the successor list is exhausted, the node falls back to be its own successor.
+     * Return the new successor.
+     */
+    pub fn remove_successor(&mut self, failed: &Location) -> Location {
+        self.successor_list.retain(|location| location != failed);
+        for finger in self.finger.iter_mut().skip(1) {
+            if finger.as_ref() == Some(failed) {
+                *finger = Some(self.location.clone());
+            }
+        }
+        let successor = match self.successor_list.first() {
+            Some(location) => location.clone(),
+            None => self.location.clone(),
+        };
+        self.update_successor(Some(successor.clone()));
+        successor
+    }
+
+    /*
+     * Handle the departure of a node at the predecessor side: if the departing
+     * node is our successor, adopt its successor instead.
+     * The departing node is dropped from the successor list and fingers anyway.
+     */
+    pub fn replace_successor(&mut self, departing: &Location, successor: Location) {
+        let was_successor = self.finger[0].as_ref() == Some(departing);
+        self.remove_successor(departing);
+        if was_successor {
+            self.set_successor(Some(successor));
+        }
     }
 
     /*
@@ -105,6 +242,89 @@ impl Node {
      * Set the location of the predecessor.
      */
     pub fn set_predecessor(&mut self, predecessor: Option<Location>) {
+        self.update_predecessor(predecessor);
+    }
+
+    /*
+     * Handle the departure of a node at the successor side: if the departing
+     * node is our predecessor, adopt its predecessor instead.
+     */
+    pub fn replace_predecessor(&mut self, departing: &Location, predecessor: Option<Location>) {
+        if self.predecessor.as_ref() == Some(departing) {
+            self.update_predecessor(predecessor);
+        }
+    }
+
+    /*
+     * Reset the node to be the only node of its own ring, as right after start.
+     */
+    pub fn reset(&mut self) {
+        self.update_predecessor(Some(self.location.clone()));
+        self.update_successor(Some(self.location.clone()));
+        for finger in self.finger.iter_mut() {
+            *finger = Some(self.location.clone());
+        }
+        self.successor_list.clear();
+    }
+
+    /*
+     * Get the state of the node.
+     */
+    pub fn get_state(&self) -> NodeState {
+        self.state
+    }
+
+    /*
+     * Set the state of the node.
+     */
+    pub fn set_state(&mut self, state: NodeState) {
+        self.state = state;
+    }
+
+    /*
+     * Fail if the node has left its cluster. It would otherwise answer as the only
+     * node of its own ring.
+     */
+    pub fn check_not_left(&self) -> Result<()> {
+        if self.state == NodeState::Left {
+            return Err(SicilyError::Peer(
+                format!("Virtual node {} has left the cluster.", self.location)
+            ));
+        }
+        Ok(())
+    }
+
+    /*
+     * Fail unless the node accepts writes to its store. It stops accepting them once
+     * it starts leaving, so that no write is left behind after the handoff.
+     */
+    pub fn check_writable(&self) -> Result<()> {
+        self.check_not_left()?;
+        if self.state == NodeState::Leaving {
+            return Err(SicilyError::Peer(
+                format!("Virtual node {} is leaving the cluster.", self.location)
+            ));
+        }
+        Ok(())
+    }
+
+    /*
+     * Overwrite the successor, which is finger[0], and count the change in the metrics.
+     */
+    fn update_successor(&mut self, successor: Option<Location>) {
+        if self.finger[0] != successor {
+            self.metrics.record_successor_change(self.location.virtual_node_id);
+        }
+        self.finger[0] = successor;
+    }
+
+    /*
+     * Overwrite the predecessor, and count the change in the metrics.
+     */
+    fn update_predecessor(&mut self, predecessor: Option<Location>) {
+        if self.predecessor != predecessor {
+            self.metrics.record_predecessor_change(self.location.virtual_node_id);
+        }
         self.predecessor = predecessor;
     }
 
@@ -122,7 +342,11 @@ impl Node {
      */
     pub fn set_finger(&mut self, n: usize, location: Option<Location>) -> Result<()> {
         validate_index(&self.finger, n)?;
-        self.finger[n] = location;
+        if n == 0 {
+            self.update_successor(location);
+        } else {
+            self.finger[n] = location;
+        }
         Ok(())
     }
 
@@ -159,6 +383,15 @@ impl Node {
         let successor_string = Location::print_info_from_option(&self.finger[0]);
         info.push_str(&format!("Successor: {}\r\n", successor_string));
 
+        /* Successor list. */
+        info.push_str(&format!("The successor list len is: {}\r\n", self.successor_list.len()));
+        for (i, location) in self.successor_list.iter().enumerate() {
+            info.push_str(&format!("Successor {}: {}\r\n", i, location.to_info()));
+        }
+
+        /* Stored keys. */
+        info.push_str(&format!("The stored key number is: {}\r\n", self.store.len()));
+
         /* Fingers and start index. */
         info.push_str(&format!("The finger list len is: {}\r\n", self.finger.len()));
         for i in 0..self.finger.len() {
@@ -172,6 +405,34 @@ impl Node {
         info
     }
 
+    /*
+     * Get the state of the node as a structured snapshot.
+     */
+    pub fn get_node_info(&self) -> NodeInfo {
+        let fingers = self.finger.iter()
+            .zip(self.finger_start_identifier.iter())
+            .map(|(finger, start)| FingerInfo {
+                start: start.to_string(),
+                location: finger.as_ref().map(LocationInfo::from),
+            })
+            .collect();
+        NodeInfo {
+            location: LocationInfo::from(&self.location),
+            predecessor: self.predecessor.as_ref().map(LocationInfo::from),
+            successor: self.finger[0].as_ref().map(LocationInfo::from),
+            successor_list: self.successor_list.iter().map(LocationInfo::from).collect(),
+            stored_keys: self.store.len(),
+            fingers,
+        }
+    }
+
+    /*
+     * Get the state of the node as a single line of JSON.
+     */
+    pub fn get_info_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&self.get_node_info()).map_err(|e| e.to_string())?)
+    }
+
     /*
      * Get the closest preceding finger of a given key.
      */
@@ -194,8 +455,10 @@ impl Node {
      * Handle the notification at the receiver side:
      * After getting notified with notifier, make a decision whether to
      * mark the notifier as new predecessor.
+     * Return whether the notifier is the predecessor after the notification, either
+     * because it has been accepted now, or because it was the predecessor already.
      */
-    pub fn notify_with(&mut self, notifier: &Location) {
+    pub fn notify_with(&mut self, notifier: &Location) -> bool {
         /* The flag to see if the current node needs to update predecessor. */
         let flag = match &self.predecessor {
             Some(predecessor) => {
@@ -207,37 +470,207 @@ impl Node {
             None => true,
         };
         if flag {
-            self.predecessor = Some(notifier.clone());
+            self.update_predecessor(Some(notifier.clone()));
+        }
+        self.predecessor.as_ref() == Some(notifier)
+    }
+
+    /*
+     * Whether the node owns the key, which is the case if the key is in
+     * range (predecessor, self]. If the predecessor is unknown, we cannot tell.
+     */
+    pub fn is_responsible_for(&self, key: &BigUint) -> bool {
+        match &self.predecessor {
+            Some(predecessor) => {
+                arithmetic::is_in_range(
+                    key,
+                    (&predecessor.identifier, false),
+                    (&self.location.identifier, true))
+            },
+            None => false,
         }
     }
+
+    /*
+     * Store a value under key in the local store.
+     */
+    pub fn put_value(&mut self, key: BigUint, value: String) {
+        self.store.insert(key, value);
+    }
+
+    /*
+     * Store a value under key in the local store, unless a value is already stored there.
+     */
+    pub fn put_value_if_absent(&mut self, key: BigUint, value: String) {
+        self.store.entry(key).or_insert(value);
+    }
+
+    /*
+     * Get the value stored under key in the local store.
+     */
+    pub fn get_value(&self, key: &BigUint) -> Option<String> {
+        self.store.get(key).cloned()
+    }
+
+    /*
+     * Delete the value stored under key from the local store.
+     */
+    pub fn delete_value(&mut self, key: &BigUint) {
+        self.store.remove(key);
+    }
+
+    /*
+     * Delete the value stored under key from the local store, unless it has been
+     * overwritten with something else than value.
+     */
+    pub fn delete_value_if_unchanged(&mut self, key: &BigUint, value: &str) {
+        if self.store.get(key).map(String::as_str) == Some(value) {
+            self.store.remove(key);
+        }
+    }
+
+    /*
+     * Remove all the key value pairs from the local store, and return them.
+     */
+    pub fn take_values(&mut self) -> Vec<(BigUint, String)> {
+        std::mem::take(&mut self.store).into_iter().collect()
+    }
+
+    /*
+     * Get all the key value pairs from the local store.
+     */
+    pub fn get_values(&self) -> Vec<(BigUint, String)> {
+        self.store
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /*
+     * Get all the key value pairs from the local store with key in range (from, to].
+     */
+    pub fn get_values_in_range(&self, from: &BigUint, to: &BigUint) -> Vec<(BigUint, String)> {
+        /* The range is walked in the store order, wrapping around the ring if from >= to. */
+        let entries: Box<dyn Iterator<Item = (&BigUint, &String)>> = if from < to {
+            Box::new(self.store.range((Excluded(from), Included(to))))
+        } else {
+            Box::new(self.store.range((Excluded(from), Unbounded)).chain(self.store.range(..=to)))
+        };
+        entries
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
 }
 
+/*
+ * The virtual nodes of the process. Each of them is behind a reader-writer lock, since
+ * most requests, lookups first, only read the routing state, while stabilization
+ * updates it now and then.
+ * The metrics and the pool of connections to other nodes belong to the node list as
+ * well, so that servers embedded in the same process don't mix them up.
+ */
 #[derive(Debug)]
 pub struct NodeList {
-    pub node_list: Vec<Mutex<Node>>,
+    pub node_list: Vec<RwLock<Node>>,
+    pub metrics: Arc<Metrics>,
+    pub pool: ConnectionPool,
+    locations: Vec<Location>,
 }
 
 impl NodeList {
     pub fn new(config: Arc<Config>) -> Self {
-        let mut node_list: Vec<Mutex<Node>> = Vec::new();
+        let metrics = Arc::new(Metrics::new());
+        let mut node_list: Vec<RwLock<Node>> = Vec::new();
+        let mut locations: Vec<Location> = Vec::new();
         for i in 0..config.virtual_node_number {
-            let node = Node::new(config.clone(), i);
-            node_list.push(Mutex::new(node));
+            let node = Node::new(config.clone(), i, metrics.clone());
+            locations.push(node.own_location());
+            node_list.push(RwLock::new(node));
         }
 
         Self {
             node_list,
+            metrics,
+            pool: ConnectionPool::new(),
+            locations,
         }
     }
+
+    /*
+     * Whether location is one of the virtual nodes in the list.
+     */
+    pub fn is_local(&self, location: &Location) -> bool {
+        self.locations.get(location.virtual_node_id as usize) == Some(location)
+    }
 }
 
 /*
  * Convenience function to validate whether an index number n is within
  * the capacity of a given vector.
  */
-fn validate_index<T>(vec: &Vec<T>, n: usize) -> Result<()> {
+fn validate_index<T>(vec: &[T], n: usize) -> Result<()> {
     if n >= vec.len() {
         return Err("Error retrieving finger. Index overflow.".into());
     }
     Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn test_node() -> (Node, Vec<Location>) {
+        let config = Arc::new(config::parse_params_from(&[
+            "sicily",
+            "--host", "127.0.0.1",
+            "--id-bits", "16",
+            "--successor-list-len", "3",
+        ]).unwrap());
+        let peers = (1..=5)
+            .map(|i| Location::from_string(format!("127.0.0.1:{}:0", 9000 + i), config.clone()).unwrap())
+            .collect();
+        (Node::new(config, 0, Arc::new(Metrics::new())), peers)
+    }
+
+    #[test]
+    fn test_successor_list_is_deduplicated_and_truncated() {
+        let (mut node, peers) = test_node();
+        let own = node.own_location();
+
+        node.update_successor_list(&peers[0], vec![peers[1].clone(), peers[0].clone(), peers[2].clone(), peers[3].clone()]);
+        assert_eq!(node.get_successor_list(), vec![peers[0].clone(), peers[1].clone(), peers[2].clone()]);
+
+        /* The list stops once it wraps around to the local node. */
+        node.update_successor_list(&peers[0], vec![peers[1].clone(), own.clone(), peers[2].clone()]);
+        assert_eq!(node.get_successor_list(), vec![peers[0].clone(), peers[1].clone()]);
+
+        /* A new successor moves to the front, without being listed twice. */
+        node.set_successor(Some(peers[1].clone()));
+        assert_eq!(node.get_successor_list(), vec![peers[1].clone(), peers[0].clone()]);
+        node.set_successor(Some(peers[3].clone()));
+        node.set_successor(Some(peers[4].clone()));
+        assert_eq!(node.get_successor_list(), vec![peers[4].clone(), peers[3].clone(), peers[1].clone()]);
+    }
+
+    #[test]
+    fn test_remove_successor_fails_over_to_next() {
+        let (mut node, peers) = test_node();
+        let own = node.own_location();
+        node.set_successor(Some(peers[0].clone()));
+        node.update_successor_list(&peers[0], vec![peers[1].clone(), peers[2].clone()]);
+        node.set_finger(3, Some(peers[0].clone())).unwrap();
+
+        assert_eq!(node.remove_successor(&peers[0]), peers[1]);
+        assert_eq!(node.get_successor().unwrap(), peers[1]);
+        assert_eq!(node.get_successor_list(), vec![peers[1].clone(), peers[2].clone()]);
+        /* Fingers pointing to the failed node are reset to the local node. */
+        assert_eq!(node.get_finger(3).unwrap(), own);
+
+        assert_eq!(node.remove_successor(&peers[1]), peers[2]);
+        /* Once the list is exhausted, the node is its own successor. */
+        assert_eq!(node.remove_successor(&peers[2]), own);
+        assert_eq!(node.get_successor().unwrap(), own);
+        assert!(node.get_successor_list().is_empty());
+    }
 }
\ No newline at end of file
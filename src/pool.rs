@@ -0,0 +1,169 @@
+/* 
+ * This file is part of the Sicily distribution (https://github.com/JeepYiheihou/sicily).
+ * Copyright (c) 2021 Jiachen Bai.
+ * 
+ * This program is free software: you can redistribute it and/or modify  
+ * it under the terms of the GNU General Public License as published by  
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU 
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License 
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use tokio::sync::{ Notify, OwnedSemaphorePermit, Semaphore };
+
+use crate::client::Client;
+use crate::config::Config;
+
+#[derive(Debug)]
+struct IdleClient {
+    client: Client,
+    since: Instant,
+}
+
+/*
+ * Connections to one peer: a permit for every open connection, idle or in use,
+ * and a notification whenever a connection is put back to the pool.
+ */
+#[derive(Debug)]
+struct Peer {
+    permits: Arc<Semaphore>,
+    released: Notify,
+}
+
+/*
+ * What a request gets from the pool: either an idle connection to reuse, or the
+ * permit to open a new one.
+ */
+pub enum Checkout {
+    Idle(Client),
+    New(OwnedSemaphorePermit),
+}
+
+/*
+ * Pool of persistent connections keyed by peer address ("host:port").
+ * Connections are taken out of the pool while in use, and put back once a
+ * request has completed successfully. A connection that failed is simply dropped,
+ * so that the next request reconnects.
+ * At most pool_max_connections_per_peer connections are open to a peer at a time.
+ * Every connection holds a permit until it is closed, and a request that finds
+ * neither an idle connection nor a permit waits for one of them.
+ * The pool keeps at most pool_max_idle_per_peer idle connections per peer, and
+ * evicts connections that have been idle for longer than pool_idle_timeout
+ * whenever a connection is taken or put back.
+ * Every server has its own pool, held by its NodeList: a connection has said hello
+ * with the config of the server that opened it, and must not be used by another one.
+ */
+#[derive(Debug)]
+pub struct ConnectionPool {
+    idle: Mutex<HashMap<String, Vec<IdleClient>>>,
+    peers: Mutex<HashMap<String, Arc<Peer>>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /*
+     * Get an idle connection to addr, or the permit to open a new one, waiting
+     * until either is available.
+     */
+    pub async fn checkout(&self, addr: &str, config: &Config) -> Checkout {
+        let peer = self.peer(addr, config);
+        loop {
+            /* Register for the notification before looking into the pool, so that
+             * a connection put back in between isn't missed. */
+            let released = peer.released.notified();
+            if let Some(client) = self.take(addr, config) {
+                return Checkout::Idle(client);
+            }
+            tokio::select! {
+                permit = peer.permits.clone().acquire_owned() => {
+                    return Checkout::New(permit.expect("Connection permits are never closed."));
+                },
+                _ = released => {},
+            }
+        }
+    }
+
+    /*
+     * Take an idle connection to addr out of the pool, if there is one.
+     * Expired connections of every peer are evicted on the way.
+     */
+    pub fn take(&self, addr: &str, config: &Config) -> Option<Client> {
+        let mut idle = self.idle.lock().unwrap();
+        evict_expired(&mut idle, config);
+        idle.get_mut(addr)?.pop().map(|idle_client| idle_client.client)
+    }
+
+    /*
+     * Put a connection to addr back to the pool after use.
+     * Expired connections of every peer are evicted on the way.
+     */
+    pub fn put(&self, addr: String, client: Client, config: &Config) {
+        {
+            let mut idle = self.idle.lock().unwrap();
+            evict_expired(&mut idle, config);
+
+            let clients = idle.entry(addr.clone()).or_default();
+            if clients.len() < config.pool_max_idle_per_peer {
+                clients.push(IdleClient {
+                    client,
+                    since: Instant::now(),
+                });
+            }
+        }
+        self.peer(&addr, config).released.notify_waiters();
+    }
+
+    /*
+     * Get the number of idle connections in the pool.
+     */
+    pub fn idle_len(&self) -> usize {
+        let idle = self.idle.lock().unwrap();
+        idle.values().map(|clients| clients.len()).sum()
+    }
+
+    /*
+     * Get the connections of addr, creating them on first use.
+     */
+    fn peer(&self, addr: &str, config: &Config) -> Arc<Peer> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(addr.to_string())
+            .or_insert_with(|| Arc::new(Peer {
+                permits: Arc::new(Semaphore::new(config.pool_max_connections_per_peer)),
+                released: Notify::new(),
+            }))
+            .clone()
+    }
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+ * Drop the connections that have been idle for longer than pool_idle_timeout,
+ * which gives their permits back.
+ */
+fn evict_expired(idle: &mut HashMap<String, Vec<IdleClient>>, config: &Config) {
+    let idle_timeout = Duration::from_millis(config.pool_idle_timeout);
+    for clients in idle.values_mut() {
+        clients.retain(|idle_client| idle_client.since.elapsed() < idle_timeout);
+    }
+    idle.retain(|_, clients| !clients.is_empty());
+}
\ No newline at end of file
@@ -17,24 +17,101 @@
 
 use num::bigint::BigUint;
 use std::sync::Arc;
+use std::time::{ Duration, Instant };
 
 use crate::arithmetic;
-use crate::command::{ Request, Response };
-use crate::config::Config;
-use crate::client::Client;
+use crate::command::{ self, Hello, Request, Response };
+use crate::config::{ Config, LookupMode };
+use crate::client;
+use crate::error::SicilyError;
 use crate::location::Location;
+use crate::node::NodeList;
 use crate::utils::Result;
 
+/*
+ * Send request to the node at location and wait for its response.
+ * If the node is a virtual node of the local process, the request is executed on it
+ * directly, without going through a loopback connection. Otherwise it is sent
+ * over the network with client::call().
+ */
+pub async fn call(
+    location: &Location,
+    request: Request,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Response> {
+    if node_list.is_local(location) {
+        return command::execute_local_request(request, node_list, config).await;
+    }
+    client::call(location, request, node_list, config).await
+}
+
 /*
  * Find successor node of a key, starting by asking node at location.
+ * The lookup is routed the way the config asks for.
  */
 pub async fn find_successor(
     location: &Location,
     key: &BigUint,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Location> {
+    let mode = config.lookup_mode;
+    find_successor_with_mode(location, key, mode, node_list, config).await
+}
+
+/*
+ * Find successor node of a key, starting by asking node at location,
+ * and routing the lookup in the given mode.
+ */
+pub async fn find_successor_with_mode(
+    location: &Location,
+    key: &BigUint,
+    mode: LookupMode,
+    node_list: Arc<NodeList>,
     config: Arc<Config>,
 ) -> Result<Location> {
-    let pred = find_predecessor(location, key, config.clone()).await?;
-    return get_successor(&pred, config).await;
+    match mode {
+        LookupMode::Iterative => {
+            let pred = find_predecessor(location, key, node_list.clone(), config.clone()).await?;
+            get_successor(&pred, node_list, config).await
+        },
+        LookupMode::Recursive => {
+            forward_find_successor(location, key, vec![location.clone()], node_list, config).await
+        },
+    }
+}
+
+/*
+ * Hand the lookup of a key over to the node at location, which resolves it
+ * by forwarding it further if needed, and returns the successor of the key.
+ * path holds the nodes the lookup visited, location last, so that the nodes along
+ * the way can give up on a revisit or once it exceeds max_lookup_hops.
+ * This function is a part of the recursive lookup process.
+ */
+pub async fn forward_find_successor(
+    location: &Location,
+    key: &BigUint,
+    path: Vec<Location>,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Location> {
+    let request = Request::FindSuccessor {
+        virtual_node_id: location.virtual_node_id,
+        key: key.clone(),
+        path,
+    };
+    let response = call(location, request, node_list, config).await?;
+    let res_location = match response {
+        Response::FindSuccessor { location } => location,
+        _ => {
+            return Err(
+                "Error receiving response while doing FINDSUCCESSOR. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(res_location)
 }
 
 /*
@@ -44,29 +121,100 @@ pub async fn find_successor(
 async fn find_predecessor(
     location: &Location,
     key: &BigUint,
-    config: Arc<Config>
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
 ) -> Result<Location> {
     let mut location = location.clone();
+    let mut path = vec![location.clone()];
     while !arithmetic::is_in_range(
         key,
         (&location.identifier, false),
-        (&get_successor(&location, config.clone()).await?.identifier, true)
+        (&get_successor(&location, node_list.clone(), config.clone()).await?.identifier, true)
     ) {
-        location = find_closest_preceding_finger(&location, &key, config.clone()).await?;
+        location = find_closest_preceding_finger(&location, key, node_list.clone(), config.clone()).await?;
+        check_next_hop(&mut path, &location, &config)?;
     }
+    node_list.metrics.record_lookup_hops(path.len());
     Ok(location)
 }
 
+/*
+ * Append the next hop of a lookup to its path. Fail with a routing error if the
+ * hop has been visited already, or if the path grows beyond the hop limit.
+ */
+pub fn check_next_hop(path: &mut Vec<Location>, next: &Location, config: &Config) -> Result<()> {
+    let revisited = path.contains(next);
+    path.push(next.clone());
+    if revisited {
+        return Err(SicilyError::routing(
+            format!("Lookup revisited {}.", next),
+            path,
+        ));
+    }
+    if path.len() > config.max_lookup_hops as usize {
+        return Err(SicilyError::routing(
+            format!("Lookup took more than {} hops.", config.max_lookup_hops),
+            path,
+        ));
+    }
+    Ok(())
+}
+
+/*
+ * A node visited by a traced lookup, with the time spent on the requests to it.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hop {
+    pub location: Location,
+    pub rtt: Duration,
+}
+
+/*
+ * Find successor node of a key, starting by asking node at location, like an
+ * iterative find_successor(). Also return every node the lookup queried, in order.
+ */
+pub async fn find_successor_traced(
+    location: &Location,
+    key: &BigUint,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<(Location, Vec<Hop>)> {
+    let mut hops = Vec::new();
+    let mut location = location.clone();
+    let mut path = vec![location.clone()];
+    loop {
+        let start = Instant::now();
+        let successor = get_successor(&location, node_list.clone(), config.clone()).await?;
+        if arithmetic::is_in_range(
+            key,
+            (&location.identifier, false),
+            (&successor.identifier, true)
+        ) {
+            hops.push(Hop {
+                location,
+                rtt: start.elapsed(),
+            });
+            node_list.metrics.record_lookup_hops(hops.len());
+            return Ok((successor, hops));
+        }
+        let next = find_closest_preceding_finger(&location, key, node_list.clone(), config.clone()).await?;
+        hops.push(Hop {
+            location,
+            rtt: start.elapsed(),
+        });
+        check_next_hop(&mut path, &next, &config)?;
+        location = next;
+    }
+}
+
 /*
  * Find successor node of a node at location.
  */
-async fn get_successor(location: &Location, config: Arc<Config>) -> Result<Location> {
+async fn get_successor(location: &Location, node_list: Arc<NodeList>, config: Arc<Config>) -> Result<Location> {
     let request = Request::GetSuccessor {
         virtual_node_id: location.virtual_node_id,
     };
-    let mut client = Client::new(location).await?;
-    client.send_request(request).await?;
-    let response = client.receive(config).await?;
+    let response = call(location, request, node_list, config).await?;
     let res_location = match response {
         Response::GetSuccessor { location } => location,
         _ => {
@@ -79,16 +227,34 @@ async fn get_successor(location: &Location, config: Arc<Config>) -> Result<Locat
     Ok(res_location)
 }
 
+/*
+ * Retrieve the successor list of a node at location.
+ */
+pub async fn get_successor_list(location: &Location, node_list: Arc<NodeList>, config: Arc<Config>) -> Result<Vec<Location>> {
+    let request = Request::GetSuccessorList {
+        virtual_node_id: location.virtual_node_id,
+    };
+    let response = call(location, request, node_list, config).await?;
+    let locations = match response {
+        Response::GetSuccessorList { locations } => locations,
+        _ => {
+            return Err(
+                "Error receiving response while doing GETSUCCESSORLIST. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(locations)
+}
+
 /*
  * Find predecessor node of a node at location.
  */
-pub async fn get_predecessor(location: &Location, config: Arc<Config>) -> Result<Option<Location>> {
+pub async fn get_predecessor(location: &Location, node_list: Arc<NodeList>, config: Arc<Config>) -> Result<Option<Location>> {
     let request = Request::GetPredecessor {
         virtual_node_id: location.virtual_node_id,
     };
-    let mut client = Client::new(location).await?;
-    client.send_request(request).await?;
-    let response = client.receive(config).await?;
+    let response = call(location, request, node_list, config).await?;
     let res_location = match response {
         Response::GetPredecessor { location } => location,
         _ => {
@@ -101,21 +267,62 @@ pub async fn get_predecessor(location: &Location, config: Arc<Config>) -> Result
     Ok(res_location)
 }
 
+/*
+ * Probe whether a node at location is alive.
+ */
+pub async fn ping(location: &Location, node_list: Arc<NodeList>, config: Arc<Config>) -> Result<()> {
+    let request = Request::Ping {
+        virtual_node_id: location.virtual_node_id,
+    };
+    let response = call(location, request, node_list, config).await?;
+    match response {
+        Response::Ping => {},
+        _ => {
+            return Err(
+                "Error receiving response while doing PING. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
+/*
+ * Say hello to a node at location. Fail with SicilyError::Incompatible if either
+ * side can't talk to the other.
+ */
+pub async fn hello(location: &Location, node_list: Arc<NodeList>, config: Arc<Config>) -> Result<()> {
+    let request = Request::Hello {
+        virtual_node_id: location.virtual_node_id,
+        hello: Hello::new(&config),
+    };
+    let response = call(location, request, node_list, config.clone()).await?;
+    match response {
+        Response::Hello { hello } => hello.check(&config)?,
+        _ => {
+            return Err(
+                "Error receiving response while doing HELLO. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
 /*
  * Find closest preceding node of a key, by finding from fingers of a node at location.
  */
 async fn find_closest_preceding_finger(
     location: &Location,
     key: &BigUint,
+    node_list: Arc<NodeList>,
     config: Arc<Config>,
 ) -> Result<Location> {
     let request = Request::ClosestPrecedingFinger {
         virtual_node_id: location.virtual_node_id,
         key: key.clone(),
     };
-    let mut client = Client::new(location).await?;
-    client.send_request(request).await?;
-    let response = client.receive(config).await?;
+    let response = call(location, request, node_list, config).await?;
     let res_location = match response {
         Response::ClosestPrecedingFinger { location } => location,
         _ => {
@@ -126,4 +333,256 @@ async fn find_closest_preceding_finger(
         }
     };
     Ok(res_location)
+}
+
+/*
+ * Store a value under key at the node at location, which stores it without routing
+ * the request any further.
+ */
+pub async fn put(
+    location: &Location,
+    key: &BigUint,
+    value: String,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let request = Request::Put {
+        virtual_node_id: location.virtual_node_id,
+        key: key.clone(),
+        value,
+        forwarded: true,
+    };
+    let response = call(location, request, node_list, config).await?;
+    match response {
+        Response::Put => {},
+        _ => {
+            return Err(
+                "Error receiving response while doing PUT. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
+/*
+ * Get the value stored under key from the node at location, which looks it up in
+ * its own store without routing the request any further.
+ */
+pub async fn get(
+    location: &Location,
+    key: &BigUint,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Option<String>> {
+    let request = Request::Get {
+        virtual_node_id: location.virtual_node_id,
+        key: key.clone(),
+        forwarded: true,
+    };
+    let response = call(location, request, node_list, config).await?;
+    let value = match response {
+        Response::Get { value } => value,
+        _ => {
+            return Err(
+                "Error receiving response while doing GET. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(value)
+}
+
+/*
+ * Delete the value stored under key from the node at location, which deletes it from
+ * its own store without routing the request any further.
+ */
+pub async fn delete(
+    location: &Location,
+    key: &BigUint,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let request = Request::Delete {
+        virtual_node_id: location.virtual_node_id,
+        key: key.clone(),
+        forwarded: true,
+    };
+    let response = call(location, request, node_list, config).await?;
+    match response {
+        Response::Delete => {},
+        _ => {
+            return Err(
+                "Error receiving response while doing DELETE. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
+/*
+ * Retrieve the key value pairs with key in range (from, to] from the node at location.
+ * The node keeps its copy until the transfer is confirmed.
+ */
+pub async fn transfer(
+    location: &Location,
+    from: &BigUint,
+    to: &BigUint,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<Vec<(BigUint, String)>> {
+    let request = Request::Transfer {
+        virtual_node_id: location.virtual_node_id,
+        from: from.clone(),
+        to: to.clone(),
+    };
+    let response = call(location, request, node_list, config).await?;
+    let entries = match response {
+        Response::Transfer { entries } => entries,
+        _ => {
+            return Err(
+                "Error receiving response while doing TRANSFER. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(entries)
+}
+
+/*
+ * Confirm to the node at location that the given key value pairs have been taken over,
+ * so that it can delete its own copy of the keys whose value is unchanged.
+ */
+pub async fn confirm_transfer(
+    location: &Location,
+    entries: Vec<(BigUint, String)>,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let request = Request::ConfirmTransfer {
+        virtual_node_id: location.virtual_node_id,
+        entries,
+    };
+    let response = call(location, request, node_list, config).await?;
+    match response {
+        Response::ConfirmTransfer => {},
+        _ => {
+            return Err(
+                "Error receiving response while doing CONFIRMTRANSFER. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
+/*
+ * Hand the key value pairs over to the node at location, which stores them unconditionally.
+ */
+pub async fn handoff(
+    location: &Location,
+    entries: Vec<(BigUint, String)>,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let request = Request::Handoff {
+        virtual_node_id: location.virtual_node_id,
+        entries,
+    };
+    let response = call(location, request, node_list, config).await?;
+    match response {
+        Response::Handoff => {},
+        _ => {
+            return Err(
+                "Error receiving response while doing HANDOFF. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
+/*
+ * Tell the node at location that its predecessor departing is leaving,
+ * and that it should adopt predecessor instead.
+ */
+pub async fn replace_predecessor(
+    location: &Location,
+    departing: &Location,
+    predecessor: Option<Location>,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let request = Request::ReplacePredecessor {
+        virtual_node_id: location.virtual_node_id,
+        departing: departing.clone(),
+        predecessor,
+    };
+    let response = call(location, request, node_list, config).await?;
+    match response {
+        Response::ReplacePredecessor => {},
+        _ => {
+            return Err(
+                "Error receiving response while doing REPLACEPREDECESSOR. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
+/*
+ * Tell the node at location that its successor departing is leaving,
+ * and that it should adopt successor instead.
+ */
+pub async fn replace_successor(
+    location: &Location,
+    departing: &Location,
+    successor: &Location,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<()> {
+    let request = Request::ReplaceSuccessor {
+        virtual_node_id: location.virtual_node_id,
+        departing: departing.clone(),
+        successor: successor.clone(),
+    };
+    let response = call(location, request, node_list, config).await?;
+    match response {
+        Response::ReplaceSuccessor => {},
+        _ => {
+            return Err(
+                "Error receiving response while doing REPLACESUCCESSOR. Got unexpected response type."
+                .into()
+            );
+        }
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_call_to_colocated_virtual_node_opens_no_connection() {
+        /* Nothing listens on port 1, so any request going over TCP fails. */
+        let (node_list, config) = testing::node_list(2, &["--port", "1", "--request-retries", "0"]);
+
+        let colocated = Location::new(config.clone(), 1);
+        let response = call(&colocated, Request::Ping { virtual_node_id: 1 }, node_list.clone(), config.clone()).await.unwrap();
+        assert!(matches!(response, Response::Ping));
+        let successor = get_successor(&colocated, node_list.clone(), config.clone()).await.unwrap();
+        assert_eq!(successor, colocated);
+
+        /* A virtual node the process doesn't host, at the same address, is reached over TCP. */
+        let remote = Location::from_string("127.0.0.1:1:7".to_string(), config.clone()).unwrap();
+        assert!(call(&remote, Request::Ping { virtual_node_id: 7 }, node_list.clone(), config.clone()).await.is_err());
+
+        let metrics = node_list.metrics.render(&node_list.pool);
+        assert!(metrics.contains("sicily_open_connections{direction=\"outbound\"} 0\n"));
+        assert_eq!(node_list.pool.idle_len(), 0);
+    }
 }
\ No newline at end of file
@@ -16,122 +16,405 @@
  */
 
 use bytes::BytesMut;
-use log;
 use std::sync::Arc;
 use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use std::net::SocketAddr;
 use tokio::net::{ TcpListener, TcpStream };
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 
+use crate::codec;
 use crate::command;
 use crate::config::Config;
+use crate::constants::*;
+use crate::error::SicilyError;
+use crate::location::{ join_host_port, split_host_port, Location };
 use crate::membership;
-use crate::node::NodeList;
+use crate::metrics;
+use crate::node::{ NodeList, NodeState };
 use crate::utils::Result;
 
-#[tokio::main]
-pub async fn start(node_list: Arc<NodeList>, config: Arc<Config>) -> Result<()> {
-    let port = config.port;
-    let output_buffer_size = config.output_buffer_size;
-    let node_list_ptr = node_list.clone();
-    let config_ptr = config.clone();
-    let handle = tokio::spawn(async move {
-        start_core_loop(
-            port,
-            output_buffer_size,
-            node_list_ptr,
-            config_ptr,
-        ).await
-    });
-
-    let config_ptr = config.clone();
-    let mut stabilizing_handles = start_stabilizing_tasks(node_list, config_ptr).await;
-    handle.await?;
-    for i in 0..stabilizing_handles.len() {
-        let handler = &mut stabilizing_handles[i];
-        handler.await?
-    }
-    Ok(())
+/*
+ * Builder of a sicily server. The server runs on the tokio runtime of the caller,
+ * so that it can be embedded in other async programs.
+ */
+pub struct ServerBuilder {
+    config: Config,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+        }
+    }
+
+    /*
+     * Listen on the given address, as host:port, instead of the configured one.
+     * With port 0 the system picks a free port, which is advertised to other nodes
+     * unless the advertised port is configured.
+     */
+    pub fn bind(mut self, bind: impl Into<String>) -> Self {
+        self.config.bind = bind.into();
+        self
+    }
+
+    /*
+     * Add a seed to join at startup, as host:port[:virtual_node_id].
+     */
+    pub fn join(mut self, seed: impl Into<String>) -> Self {
+        self.config.join.push(seed.into());
+        self
+    }
+
+    /*
+     * Start listening, and start the stabilizing tasks and the join of the seeds
+     * in the background. Return once the server is listening.
+     */
+    pub async fn start(self) -> Result<ServerHandle> {
+        let mut config = self.config;
+        let listener = TcpListener::bind(&config.bind).await
+            .map_err(|e| format!("Cannot listen on {}: {}", config.bind, e))?;
+        let local_addr = listener.local_addr()?;
+        if config.advertise_port == 0 {
+            config.advertise_port = local_addr.port();
+        }
+        let config = Arc::new(config);
+        let node_list = Arc::new(NodeList::new(config.clone()));
+
+        /* Parse the seeds before anything starts, so that a typo fails right away. */
+        let mut seeds = Vec::new();
+        for seed in config.join.iter() {
+            let location = Location::from_string(seed.clone(), config.clone())
+                .map_err(|e| format!("Invalid seed {}: {}", seed, e))?;
+            seeds.push(location);
+        }
+
+        log::info!(
+            "Listening on {}, advertised to other nodes as {}:{}",
+            local_addr,
+            config.host,
+            config.advertise_port
+        );
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let listener_handle = tokio::spawn(start_core_loop(
+            listener,
+            node_list.clone(),
+            config.clone(),
+            shutdown_receiver,
+        ));
+
+        let mut background_handles = Vec::new();
+        if let Some(metrics_port) = config.metrics_port {
+            /* Serve the metrics on the same host as the requests. */
+            let (bind_host, _) = split_host_port(&config.bind)?;
+            let metrics_addr = join_host_port(&bind_host, metrics_port);
+            background_handles.push(tokio::spawn(metrics::serve(metrics_addr, node_list.clone())));
+        }
+        background_handles.extend(start_stabilizing_tasks(node_list.clone(), config.clone()).await);
+
+        /* Join the seeds in the background, so that the node keeps serving meanwhile.
+         * The outcome is published once every virtual node has joined, or the join failed. */
+        let (ready_sender, ready_receiver) = watch::channel(None);
+        let node_list_ptr = node_list.clone();
+        let config_ptr = config.clone();
+        background_handles.push(tokio::spawn(async move {
+            let result = membership::bootstrap(seeds, node_list_ptr, config_ptr.clone()).await;
+            match &result {
+                Ok(()) => log::info!(
+                    "Ready. All {} virtual nodes have joined the cluster.",
+                    config_ptr.virtual_node_number
+                ),
+                Err(e) => log::error!("Cannot join the cluster. Error message: {}.", e),
+            }
+            let _ = ready_sender.send(Some(result));
+        }));
+
+        Ok(ServerHandle {
+            local_addr,
+            node_list,
+            config,
+            shutdown_sender,
+            ready_receiver,
+            listener_handle,
+            background_handles,
+        })
+    }
+}
+
+/*
+ * Handle of a running sicily server. Dropping the handle leaves the server running,
+ * call shutdown() to stop it.
+ */
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+    shutdown_sender: watch::Sender<bool>,
+    ready_receiver: watch::Receiver<Option<Result<()>>>,
+    listener_handle: JoinHandle<()>,
+    background_handles: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /*
+     * The address the server listens on.
+     */
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn node_list(&self) -> Arc<NodeList> {
+        self.node_list.clone()
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.clone()
+    }
+
+    /*
+     * Wait until every virtual node has joined the cluster through the seeds, which is
+     * right away without seeds. Fail if the server cannot join, for example because the
+     * seeds belong to another cluster.
+     */
+    pub async fn ready(&self) -> Result<()> {
+        let mut ready_receiver = self.ready_receiver.clone();
+        loop {
+            if let Some(result) = ready_receiver.borrow().clone() {
+                return result;
+            }
+            if ready_receiver.changed().await.is_err() {
+                return Err(SicilyError::Internal("Server stopped before being ready.".to_string()));
+            }
+        }
+    }
+
+    /*
+     * Shut the server down gracefully. The stabilizing tasks are stopped first, then
+     * every virtual node leaves the cluster while the listener is still serving.
+     * Finally the listener and the open connections are closed, letting the requests
+     * in flight finish.
+     */
+    pub async fn shutdown(self) {
+        for handle in self.background_handles.iter() {
+            handle.abort();
+        }
+        for handle in self.background_handles {
+            let _ = handle.await;
+        }
+        leave_all(self.node_list.clone(), self.config.clone()).await;
+        let _ = self.shutdown_sender.send(true);
+        let _ = self.listener_handle.await;
+        log::info!("Server on {} shut down.", self.local_addr);
+    }
+}
+
+/*
+ * Wait until the server is shut down. If the handle was dropped without shutting
+ * down, the server keeps running forever.
+ */
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/*
+ * Let every virtual node leave the cluster.
+ */
+async fn leave_all(node_list: Arc<NodeList>, config: Arc<Config>) {
+    for i in 0..config.virtual_node_number {
+        if let Err(e) = membership::leave(i, node_list.clone(), config.clone()).await {
+            log::error!("Error leaving at virtual node id {}. Error message: {}.", i, e);
+        }
+    }
 }
 
 async fn start_core_loop(
-    port: u16,
-    output_buffer_size: usize,
+    listener: TcpListener,
     node_list: Arc<NodeList>,
     config: Arc<Config>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
-    let listener = match TcpListener::bind(&format!("127.0.0.1:{}", port)).await {
-        Ok(listener) => { listener }
-        Err(e) => {
-            log::error!("Error initializing listener. Error log: {}", e);
-            return;
-        }
-    };
-    log::info!("Listening to port: {}", port);
+    let output_buffer_size = config.output_buffer_size;
     loop {
         let node_list = node_list.clone();
-        let stream: TcpStream;
-
-        match listener.accept().await {
-            Ok((s, _)) => {
-                stream = s
-            }
-            Err(e) => {
-                log::error!("Error accepting. Error log: {}", e);
-                continue;
-            } 
+        let stream: TcpStream = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((s, _)) => {
+                    s
+                }
+                Err(e) => {
+                    log::error!("Error accepting. Error log: {}", e);
+                    continue;
+                } 
+            },
+            _ = wait_for_shutdown(&mut shutdown) => {
+                return;
+            },
         };
 
         let config_ptr = config.clone();
+        let shutdown = shutdown.clone();
         tokio::spawn(async move {
+            let metrics = node_list.metrics.clone();
+            metrics.inbound_connection_opened();
             handle_socket_read(
                 stream,
                 output_buffer_size,
                 node_list,
                 config_ptr,
+                shutdown,
             ).await;
+            metrics.inbound_connection_closed();
         });
     }
 }
 
+/*
+ * The protocol a connection talks, decided by its first byte.
+ */
+#[derive(Clone, Copy)]
+enum Protocol {
+    Text,
+    Binary,
+}
+
 async fn handle_socket_read(
     mut stream: TcpStream,
     output_buffer_size: usize,
     node_list: Arc<NodeList>,
     config: Arc<Config>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
     let mut buf = BytesMut::with_capacity(output_buffer_size);
+
+    /* The first byte tells whether the connection talks the binary protocol,
+     * which is used between nodes, or the text protocol. */
+    if !read_to_buf(&mut stream, &mut buf, &mut shutdown).await {
+        return;
+    }
+    let protocol = if buf[0] == BINARY_PROTOCOL_HANDSHAKE {
+        let _ = buf.split_to(1);
+        Protocol::Binary
+    } else {
+        Protocol::Text
+    };
+
+    /* A read may carry a partial request, or several pipelined ones.
+     * Process every complete request received so far, and write the responses in order. */
+    let mut output = BytesMut::new();
+    let mut greeted = false;
     loop {
-        match stream.read_buf(&mut buf).await {
-            Ok(n) => {
-                if n == 0 {
+        loop {
+            match process_next_request(
+                protocol,
+                &mut buf,
+                &mut output,
+                &mut greeted,
+                node_list.clone(),
+                config.clone(),
+            ).await {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(err) => {
+                    /* The stream can't be framed any more, or the peer didn't say hello.
+                     * Tell the client why, and close the connection. */
+                    log::error!("Could not process request: {}", err);
+                    let err = err.refine(SicilyError::Parse);
+                    match protocol {
+                        Protocol::Binary => {
+                            let _ = err.encode(&mut output);
+                        },
+                        Protocol::Text => {
+                            output.extend_from_slice(err.serialize(false).as_bytes());
+                        },
+                    }
+                    write_to_socket(&mut stream, &output).await;
                     return;
                 }
+            }
+        }
+        if !output.is_empty() {
+            write_to_socket(&mut stream, &output).await;
+            output.clear();
+        }
+        if !read_to_buf(&mut stream, &mut buf, &mut shutdown).await {
+            return;
+        }
+    }
+}
 
-                match command::process_request(
-                    &buf,
-                    node_list.clone(),
-                    config.clone()
-                ).await {
-                    Ok(string) => {
-                        write_to_socket(&mut stream, string).await;
-                        buf.clear();
-                    },
-                    Err(err) => {
-                        log::error!("Could not process request: {}", err);
-                        return;
-                    }
-                };
-            },
+/*
+ * Split the next complete request off buf, execute it, and append the response to output.
+ * Return false if buf doesn't hold a complete request yet.
+ * On the binary protocol, nothing but HELLO is served until a HELLO succeeded, which greeted
+ * keeps track of.
+ */
+async fn process_next_request(
+    protocol: Protocol,
+    buf: &mut BytesMut,
+    output: &mut BytesMut,
+    greeted: &mut bool,
+    node_list: Arc<NodeList>,
+    config: Arc<Config>,
+) -> Result<bool> {
+    match protocol {
+        Protocol::Binary => {
+            let frame = match codec::take_frame(buf)? {
+                Some(frame) => frame,
+                None => return Ok(false),
+            };
+            if !*greeted && !codec::is_hello(&frame) {
+                return Err(SicilyError::Incompatible(
+                    "The first request of a binary connection must be HELLO.".to_string()
+                ));
+            }
+            let succeeded = command::process_binary_request(&frame, output, node_list, config).await?;
+            *greeted = *greeted || succeeded;
+        },
+        Protocol::Text => {
+            let line = match codec::take_line(buf)? {
+                Some(line) => line,
+                None => return Ok(false),
+            };
+            /* Skip empty lines, for example when a human client just hits enter. */
+            if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+                return Ok(true);
+            }
+            let string = command::process_request(&line, node_list, config).await;
+            output.extend_from_slice(string.as_bytes());
+        },
+    }
+    Ok(true)
+}
+
+/*
+ * Read more bytes from the socket into buf.
+ * Return false if the connection is closed or broken, or the server is shut down.
+ */
+async fn read_to_buf(
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool {
+    tokio::select! {
+        res = stream.read_buf(buf) => match res {
+            Ok(0) => false,
+            Ok(_) => true,
             Err(e) => {
                 log::error!("Error reading from socket: {}", e);
-                return;
+                false
             }
-        }
+        },
+        _ = wait_for_shutdown(shutdown) => false,
     }
 }
 
-async fn write_to_socket(stream: &mut TcpStream, string: String) {
-    match stream.write_all(string.as_bytes()).await {
+async fn write_to_socket(stream: &mut TcpStream, bytes: &[u8]) {
+    match stream.write_all(bytes).await {
         Ok(_) => {},
         Err(err) => {
             log::error!("Error when writing repsonse to buffer: {}", err);
@@ -160,21 +443,183 @@ async fn start_stabilizing_task(
     loop {
         tokio::time::sleep(tokio::time::Duration::from_millis(config.stabilize_frequency)).await;
         let node_list = node_list.clone();
+
+        /* A virtual node that left its cluster stays out of it until it joins again. */
+        if node_list.node_list[virtual_node_id as usize].read().await.get_state() == NodeState::Left {
+            continue;
+        }
         match membership::stablize(virtual_node_id, node_list.clone(), config.clone()).await {
             Ok(()) => {
-                /* Happy case. Nothing to do. */
+                node_list.metrics.record_stabilize(true);
             },
             Err(e) => {
+                node_list.metrics.record_stabilize(false);
                 log::error!{"Error stabilizing at virtual node id {}. Error message: {}.", virtual_node_id, e};
             }
         }
         match membership::fix_fingers(virtual_node_id, node_list.clone(), config.clone()).await {
             Ok(()) =>{
-                /* Happy case. Nothing to do. */
+                node_list.metrics.record_fix_fingers(true);
             },
             Err(e) => {
+                node_list.metrics.record_fix_fingers(false);
                 log::error!("Error fixing fingers at virtual node id {}. Error message: {}.", virtual_node_id, e);
             }
         }
+        match membership::check_predecessor(virtual_node_id, node_list.clone(), config.clone()).await {
+            Ok(()) => {
+                node_list.metrics.record_check_predecessor(true);
+            },
+            Err(e) => {
+                node_list.metrics.record_check_predecessor(false);
+                log::error!("Error checking predecessor at virtual node id {}. Error message: {}.", virtual_node_id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client;
+    use crate::command::Request;
+    use crate::config;
+    use crate::testing;
+    use std::time::Duration;
+
+    fn test_config() -> Config {
+        config::parse_params_from(&[
+            "sicily",
+            "--host", "127.0.0.1",
+            "--port", "0",
+            "--id-bits", "16",
+            "--virtual-node-number", "2",
+            "--stabilize-frequency", "50",
+        ]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_embedded_servers_join_and_shut_down() {
+        let first = ServerBuilder::new(test_config()).start().await.unwrap();
+        assert_ne!(first.local_addr().port(), 0);
+        assert_eq!(first.config().advertise_port, first.local_addr().port());
+
+        let second = ServerBuilder::new(test_config())
+            .join(format!("127.0.0.1:{}", first.local_addr().port()))
+            .start().await.unwrap();
+
+        first.ready().await.unwrap();
+        second.ready().await.unwrap();
+        let node_list = second.node_list();
+        for i in 0..2 {
+            let node = node_list.node_list[i].read().await;
+            assert_ne!(node.get_successor().unwrap(), node.own_location());
+        }
+
+        let addr = second.local_addr();
+        second.shutdown().await;
+        assert!(TcpStream::connect(addr).await.is_err());
+        first.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_server_joining_incompatible_seed_fails_to_get_ready() {
+        let first = ServerBuilder::new(test_config()).start().await.unwrap();
+        let mut config = test_config();
+        config.cluster_name = "other".to_string();
+        let second = ServerBuilder::new(config)
+            .join(format!("127.0.0.1:{}", first.local_addr().port()))
+            .start().await.unwrap();
+
+        let err = tokio::time::timeout(Duration::from_secs(5), second.ready()).await.unwrap().unwrap_err();
+        assert!(matches!(err, SicilyError::Incompatible(_)));
+        second.shutdown().await;
+        first.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_join_of_incompatible_node_is_rejected() {
+        let server = ServerBuilder::new(test_config()).start().await.unwrap();
+        let target = Location::new(server.config(), 0);
+
+        for args in [["--cluster-name", "other", "--id-bits", "16"], ["--cluster-name", "sicily", "--id-bits", "24"]] {
+            let mut params = vec![
+                "sicily",
+                "--host", "127.0.0.1",
+                "--port", "1",
+                "--virtual-node-number", "1",
+            ];
+            params.extend_from_slice(&args);
+            let config = Arc::new(config::parse_params_from(&params).unwrap());
+            let node_list = Arc::new(NodeList::new(config.clone()));
+
+            let err = membership::join(0, target.clone(), node_list.clone(), config).await.unwrap_err();
+            assert!(matches!(err, SicilyError::Incompatible(_)));
+            let node = node_list.node_list[0].read().await;
+            assert_eq!(node.get_successor().unwrap(), node.own_location());
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_internal_requests_are_rejected_on_text_protocol() {
+        let server = ServerBuilder::new(test_config()).start().await.unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        stream.write_all(b"REPLACESUCCESSOR 0 127.0.0.1:1:0 127.0.0.1:1:1\r\nPUT 0 42 hello FORWARDED\r\nNOTIFY 0 127.0.0.1:1:0\r\nPING 0\r\n").await.unwrap();
+
+        let mut buf = BytesMut::new();
+        while buf.iter().filter(|byte| **byte == b'\n').count() < 4 {
+            assert_ne!(stream.read_buf(&mut buf).await.unwrap(), 0);
+        }
+        let response = String::from_utf8(buf.to_vec()).unwrap();
+        let lines: Vec<&str> = response.lines().collect();
+        assert!(lines[0].starts_with("ERR INCOMPATIBLE"));
+        assert!(lines[1].starts_with("ERR INCOMPATIBLE"));
+        assert!(lines[2].starts_with("RES NOTIFY"));
+        assert!(lines[3].starts_with("RES PING"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_embedded_servers_have_their_own_metrics() {
+        let first = ServerBuilder::new(test_config()).start().await.unwrap();
+        let second = ServerBuilder::new(test_config()).start().await.unwrap();
+
+        let mut stream = TcpStream::connect(first.local_addr()).await.unwrap();
+        stream.write_all(b"PING 0\r\n").await.unwrap();
+        let mut buf = BytesMut::new();
+        while !buf.ends_with(b"\n") {
+            assert_ne!(stream.read_buf(&mut buf).await.unwrap(), 0);
+        }
+
+        assert!(first.node_list().metrics.render(&first.node_list().pool).contains("sicily_requests_total{request=\"ping\"} 1\n"));
+        assert!(!second.node_list().metrics.render(&second.node_list().pool).contains("request=\"ping\""));
+        first.shutdown().await;
+        second.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_pooled_connections_are_not_shared_between_servers() {
+        let server = ServerBuilder::new(test_config()).start().await.unwrap();
+        let target = Location::new(server.config(), 0);
+
+        let node_list = |cluster_name: &str| {
+            testing::node_list(1, &["--port", "1", "--cluster-name", cluster_name])
+        };
+        let ping = Request::Ping { virtual_node_id: 0 };
+
+        /* The connection opened by a node of the same cluster stays in its own pool. */
+        let (same, config) = node_list("sicily");
+        client::call(&target, ping, same.clone(), config).await.unwrap();
+        assert_eq!(same.pool.idle_len(), 1);
+
+        /* A node of another cluster says hello on a new connection, and is refused. */
+        let (other, config) = node_list("other");
+        let ping = Request::Ping { virtual_node_id: 0 };
+        let err = client::call(&target, ping, other.clone(), config).await.unwrap_err();
+        assert!(matches!(err, SicilyError::Incompatible(_)));
+        assert_eq!(other.pool.idle_len(), 0);
+        assert_eq!(same.pool.idle_len(), 1);
+        server.shutdown().await;
     }
 }
\ No newline at end of file
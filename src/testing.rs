@@ -0,0 +1,130 @@
+/* 
+ * This file is part of the Sicily distribution (https://github.com/JeepYiheihou/sicily).
+ * Copyright (c) 2021 Jiachen Bai.
+ * 
+ * This program is free software: you can redistribute it and/or modify  
+ * it under the terms of the GNU General Public License as published by  
+ * the Free Software Foundation, version 3.
+ *
+ * This program is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of 
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU 
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License 
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+/*
+ * Fixtures shared by the tests of the crate. The virtual nodes they build belong to
+ * a single process without any server, talk to each other without TCP, and only
+ * stabilize when the test says so.
+ */
+
+use num::BigUint;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::command::{ self, Request, Response };
+use crate::config::{ self, Config };
+use crate::location::Location;
+use crate::membership;
+use crate::node::{ NodeList, NodeState };
+
+/*
+ * Build the virtual nodes of a process, configured with the given extra commandline
+ * arguments. The environment of the test run is ignored.
+ */
+pub fn node_list(virtual_node_number: u8, extra_args: &[&str]) -> (Arc<NodeList>, Arc<Config>) {
+    let virtual_node_number_arg = virtual_node_number.to_string();
+    let mut args = vec![
+        "sicily",
+        "--host", "127.0.0.1",
+        "--id-bits", "16",
+        "--virtual-node-number", &virtual_node_number_arg,
+    ];
+    args.extend_from_slice(extra_args);
+    let config = Arc::new(config::parse_params_from_env(&args, &HashMap::new()).unwrap());
+    let node_list = Arc::new(NodeList::new(config.clone()));
+    (node_list, config)
+}
+
+/*
+ * The locations of the virtual nodes of the process, sorted by identifier.
+ */
+pub fn sorted_locations(config: &Arc<Config>) -> Vec<Location> {
+    let mut locations: Vec<Location> = (0..config.virtual_node_number)
+        .map(|i| Location::new(config.clone(), i))
+        .collect();
+    locations.sort_by(|x, y| x.identifier.cmp(&y.identifier));
+    locations
+}
+
+/*
+ * Build a ring of virtual_node_number virtual nodes, all joined through the first one,
+ * and stabilize it.
+ */
+pub async fn ring(virtual_node_number: u8) -> (Arc<NodeList>, Arc<Config>) {
+    let (node_list, config) = node_list(virtual_node_number, &[]);
+    let first = Location::new(config.clone(), 0);
+    for i in 1..virtual_node_number {
+        membership::join(i, first.clone(), node_list.clone(), config.clone()).await.unwrap();
+    }
+    stabilize_rounds(&node_list, &config, virtual_node_number as usize + 1).await;
+    (node_list, config)
+}
+
+/*
+ * Stabilize every virtual node that hasn't left, rounds times.
+ */
+pub async fn stabilize_rounds(node_list: &Arc<NodeList>, config: &Arc<Config>, rounds: usize) {
+    for _ in 0..rounds {
+        for i in 0..config.virtual_node_number {
+            if node_list.node_list[i as usize].read().await.get_state() == NodeState::Left {
+                continue;
+            }
+            membership::stablize(i, node_list.clone(), config.clone()).await.unwrap();
+        }
+    }
+}
+
+pub async fn request(node_list: &Arc<NodeList>, config: &Arc<Config>, request: Request) -> Response {
+    command::execute_local_request(request, node_list.clone(), config.clone()).await.unwrap()
+}
+
+pub async fn get(node_list: &Arc<NodeList>, config: &Arc<Config>, virtual_node_id: u8, key: &BigUint) -> Option<String> {
+    let response = request(node_list, config, Request::Get {
+        virtual_node_id,
+        key: key.clone(),
+        forwarded: false,
+    }).await;
+    match response {
+        Response::Get { value } => value,
+        other => panic!("Unexpected response {:?}", other),
+    }
+}
+
+/*
+ * The value a virtual node stores under key itself, without routing.
+ */
+pub async fn stored(node_list: &Arc<NodeList>, virtual_node_id: u8, key: &BigUint) -> Option<String> {
+    let node = node_list.node_list[virtual_node_id as usize].read().await;
+    node.get_value(key)
+}
+
+/*
+ * Put 64 keys spread over the identifier space through a virtual node, with values
+ * derived from the keys, and return the keys.
+ */
+pub async fn put_keys(node_list: &Arc<NodeList>, config: &Arc<Config>, virtual_node_id: u8) -> Vec<BigUint> {
+    let keys: Vec<BigUint> = (0..64u32).map(|i| BigUint::from(i * 1024 + 7)).collect();
+    for key in keys.iter() {
+        request(node_list, config, Request::Put {
+            virtual_node_id,
+            key: key.clone(),
+            value: format!("value-{}", key),
+            forwarded: false,
+        }).await;
+    }
+    keys
+}
\ No newline at end of file
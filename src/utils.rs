@@ -15,4 +15,6 @@
  * along with this program. If not, see <http://www.gnu.org/licenses/>.
  */
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
\ No newline at end of file
+use crate::error::SicilyError;
+
+pub type Result<T> = std::result::Result<T, SicilyError>;
\ No newline at end of file